@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{
+    append_jsonl, is_guest, open_jsonl, validate_username, GUEST_PREFIX, MIN_PASSWORD_LEN,
+};
+
+/// A registered name, as kept in the accounts file.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Account {
+    username: String,
+    /// Salted argon2 hash, in PHC string format.
+    hash: String,
+}
+
+/// Registered usernames and their password hashes, kept in memory and
+/// appended to a file so they survive restarts. Each line of the file is a
+/// JSON `Account`.
+pub struct Accounts {
+    file: Option<File>,
+    hashes: HashMap<String, String>,
+}
+
+impl Accounts {
+    /// Accounts that are gone once the server stops.
+    pub fn in_memory() -> Self {
+        Accounts {
+            file: None,
+            hashes: HashMap::new(),
+        }
+    }
+
+    /// Accounts registered in `path` so far, with new ones saved there too.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut hashes = HashMap::new();
+        let file = open_jsonl(path, |account: Account| {
+            hashes.insert(account.username, account.hash);
+        })?;
+
+        Ok(Accounts {
+            file: Some(file),
+            hashes,
+        })
+    }
+
+    pub fn is_registered(&self, username: &str) -> bool {
+        self.hashes.contains_key(username)
+    }
+
+    /// Whether `username` can be registered with `password`.
+    pub fn check_new(&self, username: &str, password: &str) -> Result<(), String> {
+        validate_username(username)?;
+
+        if is_guest(username) {
+            return Err(format!(
+                "Names starting with {} are for guests",
+                GUEST_PREFIX
+            ));
+        }
+
+        if self.is_registered(username) {
+            return Err(format!("{} is already registered", username));
+        }
+
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(format!(
+                "Password needs at least {} chars",
+                MIN_PASSWORD_LEN
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// The hash `username` was registered with, if it was.
+    pub fn hash_of(&self, username: &str) -> Option<&str> {
+        self.hashes.get(username).map(String::as_str)
+    }
+
+    /// Reserves `username` for whoever knows the password behind `hash`.
+    pub fn add(&mut self, username: &str, hash: String) -> Result<(), String> {
+        // Someone may have been quicker while the password was hashed
+        if self.is_registered(username) {
+            return Err(format!("{} is already registered", username));
+        }
+
+        if let Some(file) = self.file.as_mut() {
+            let account = Account {
+                username: username.to_owned(),
+                hash: hash.clone(),
+            };
+            append_jsonl(file, &account).map_err(|e| format!("Could not save account: {}", e))?;
+        }
+
+        self.hashes.insert(username.to_owned(), hash);
+
+        Ok(())
+    }
+}
+
+/// Salts and hashes `password` for keeping. Slow on purpose.
+pub fn hash(password: &str) -> Result<String, String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| format!("Could not hash password: {}", e))
+}
+
+/// Whether `password` is the one behind `hash`. Slow on purpose.
+pub fn verify(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
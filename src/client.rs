@@ -1,98 +1,536 @@
+use std::collections::hash_map::RandomState;
+use std::collections::VecDeque;
+use std::hash::{BuildHasher, Hasher};
 use std::io::prelude::*;
-use std::io::{stdin, stdout};
-use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::io::{self, stdin, stdout, IsTerminal};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::process;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::{Arc, RwLock};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::{self};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crate::common::{self, read_messages, send_string, setup_stream};
+use chrono::Local;
+use rustls::pki_types::ServerName;
 
-pub fn join(addr: SocketAddr, username: Option<&str>) {
-    let mut stream = Arc::new(RwLock::new(
-        TcpStream::connect(&addr).expect("Failed to connect to server."),
-    ));
-    println!("Connected {}", addr.to_string());
+use crate::commands::{Context, Line, Outcome, Registry};
+use crate::common::{
+    is_guest, read_message, read_messages, send_message, setup_stream, validate_username,
+    Connection, ErrorCode, Feature, HandshakeError, Message, ServerError, DEFAULT_HEARTBEAT,
+    DEFAULT_MAX_FRAME_SIZE, DEFAULT_ROOM, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use crate::dispatch::DEFAULT_COMMANDS;
+use crate::input::{self, Input};
+use crate::output::{print_json, say, Output};
+use crate::tls;
+use crate::transport::{Endpoint, Transport};
+use crate::tui::{self, Feed};
 
-    let username = username.map_or_else(get_username, |u| u.into());
+/// Protocol extensions this client knows how to handle.
+const CLIENT_FEATURES: &[Feature] = &[Feature::History, Feature::Rooms, Feature::Users];
 
-    loop {
-        if let Ok(mut stream) = stream.try_write() {
-            setup_stream(&stream).expect("Failed to setup connection");
+/// Exit status when the connection was lost and we gave up getting it back.
+pub const EXIT_CONNECTION_LOST: i32 = 2;
 
-            handshake(&mut stream, &username);
+/// Exit status when the server shut down after telling us so.
+pub const EXIT_SERVER_SHUTDOWN: i32 = 3;
 
-            break;
+/// Wait before the first reconnection attempt. Doubles after each failure.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// How many times to try reaching the server, or getting a lost connection
+/// back, before giving up.
+const MAX_RECONNECT_ATTEMPTS: u32 = 10;
+
+/// The server counts as gone after this many heartbeats without a word.
+const HEARTBEAT_MISSES: u32 = 3;
+
+/// Knobs the client is started with.
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub max_frame_size: usize,
+    /// The server gets pinged after this long without hearing from it.
+    pub heartbeat: Duration,
+    /// Talk to the server over TLS when set.
+    pub tls: Option<Arc<rustls::ClientConfig>>,
+    pub output: Output,
+    /// Full screen, instead of line by line.
+    pub tui: bool,
+    /// Where what's typed is kept between runs, in a file per server.
+    pub history_dir: Option<PathBuf>,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            heartbeat: DEFAULT_HEARTBEAT,
+            tls: None,
+            output: Output::Text,
+            tui: false,
+            history_dir: None,
         }
     }
+}
+
+/// What the reader thread learns from the server that changes what we send.
+pub struct Session {
+    /// Our name, which `/nick` can change.
+    pub username: RwLock<String>,
+    /// Password for our name if it's registered, so reconnecting doesn't
+    /// have to ask again.
+    pub password: RwLock<Option<String>>,
+    /// Name and password sent with `/register`, until the server renames us.
+    pub registering: RwLock<Option<(String, String)>>,
+    /// Room our messages go to, switched whenever we join or leave one.
+    pub room: RwLock<String>,
+    /// Last user that messaged us directly, for `/reply`.
+    pub reply_to: RwLock<Option<String>>,
+    /// Why the server said it's shutting down, if it did.
+    pub shutdown: RwLock<Option<String>>,
+    /// Set once we said goodbye, so the connection closing is no surprise.
+    pub quitting: AtomicBool,
+    /// Cleared while the connection is down.
+    pub online: AtomicBool,
+    /// Messages typed while offline, sent once we're back.
+    pub pending: Mutex<VecDeque<Message>>,
+    /// How what comes in is printed, and where our own lines go.
+    pub output: Output,
+    /// Where everything to show goes instead when running the TUI.
+    pub feed: Option<Sender<Feed>>,
+    /// Who's online as far as we know, sorted.
+    pub users: Arc<RwLock<Vec<String>>>,
+    /// What the server agreed to.
+    pub features: Vec<Feature>,
+}
 
+impl Session {
+    /// Shows a line of our own, as opposed to something from the server.
+    pub fn say(&self, line: &str) {
+        match &self.feed {
+            Some(feed) => {
+                let _ = feed.send(Feed::Notice(line.into()));
+            }
+            None => say(self.output, line),
+        }
+    }
+
+    /// Shows something that went wrong on our side.
+    pub fn complain(&self, reason: &str) {
+        match &self.feed {
+            Some(feed) => {
+                let _ = feed.send(Feed::Error(reason.into()));
+            }
+            None => eprintln!("ERROR: {}", reason),
+        }
+    }
+
+    /// Shows what came in from the server.
+    fn show(&self, msg: &Message) {
+        match (&self.feed, self.output) {
+            (Some(feed), _) => {
+                let _ = feed.send(Feed::Message(msg.clone()));
+            }
+            (None, Output::Text) => print_message(msg),
+            (None, Output::Json) => print_json(msg),
+        }
+    }
+
+    /// Wipes everything shown so far.
+    pub fn clear(&self) {
+        match &self.feed {
+            Some(feed) => {
+                let _ = feed.send(Feed::Clear);
+            }
+            None => {
+                print!("\x1B[2J\x1B[1;1H");
+                let _ = stdout().flush();
+            }
+        }
+    }
+
+    /// Quits with `code`. The TUI does it itself, once the terminal is back
+    /// to normal, so this returns when there's one.
+    fn exit(&self, code: i32) {
+        match &self.feed {
+            Some(feed) => {
+                let _ = feed.send(Feed::Exit(code));
+            }
+            None => {
+                // Line editing may still be waiting for a line
+                input::restore_terminal();
+                process::exit(code)
+            }
+        }
+    }
+}
+
+/// Who we are to the server.
+#[derive(Debug, Clone)]
+pub struct Credentials {
+    pub username: String,
+    /// Only needed for registered names.
+    pub password: Option<String>,
+}
+
+pub fn join(
+    endpoint: Endpoint,
+    username: Option<&str>,
+    password: Option<String>,
+    config: ClientConfig,
+) {
     let running = Arc::new(AtomicBool::new(true));
     let running_clone = running.clone();
+    let output = config.output;
     ctrlc::set_handler(move || {
-        println!("Received Ctrl-C");
+        say(output, "Received Ctrl-C");
         running_clone.store(false, Ordering::SeqCst);
     })
     .expect("Failed to set ctrl-c handler");
 
+    let mut conn = None;
+    for attempt in 0..MAX_RECONNECT_ATTEMPTS {
+        if !running.load(Ordering::SeqCst) {
+            return;
+        }
+
+        match connect(&endpoint, &config) {
+            Ok(c) => {
+                conn = Some(c);
+                break;
+            }
+            Err(e) if attempt + 1 == MAX_RECONNECT_ATTEMPTS => {
+                eprintln!("Could not connect to {} ({}), giving up", endpoint, e);
+                process::exit(EXIT_CONNECTION_LOST);
+            }
+            Err(e) => {
+                let delay = backoff(attempt);
+                let line = format!(
+                    "*** Could not connect to {} ({}), retrying in {:.1}s",
+                    endpoint,
+                    e,
+                    delay.as_secs_f32()
+                );
+                say(config.output, &line);
+                thread::sleep(delay);
+            }
+        }
+    }
+    let mut conn = conn.expect("Connected");
+    if config.tls.is_some() && matches!(endpoint, Endpoint::Tcp(_)) {
+        say(config.output, &format!("Connected {} over TLS", endpoint));
+    } else {
+        say(config.output, &format!("Connected {}", endpoint));
+    }
+
+    let requested = Credentials {
+        username: username.map_or_else(|| get_username(config.output), |u| u.into()),
+        password,
+    };
+    let (credentials, features) = match handshake(&mut conn, requested, Some(config.output)) {
+        Ok(accepted) => accepted,
+        Err(e) => {
+            eprintln!("Could not join the server: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let (feed, feed_receiver) = channel();
+    let users = Arc::new(RwLock::new(vec![credentials.username.clone()]));
+    // Only for someone typing. Lines piped in aren't worth remembering, and
+    // with JSON output prompts stay off stdout
+    let line_mode = !config.tui && config.output == Output::Text && stdin().is_terminal();
+    // The server's own commands complete too
+    let mut commands = Registry::new().names();
+    commands.extend(DEFAULT_COMMANDS.iter().map(|&(name, _)| name));
+    commands.sort_unstable();
+    commands.dedup();
+    let input = match line_mode {
+        true => Input::new(
+            &endpoint,
+            config.history_dir.as_deref(),
+            commands,
+            users.clone(),
+        )
+        .map_err(|e| eprintln!("ERROR: Line editing isn't available: {}", e))
+        .ok(),
+        false => None,
+    };
+
+    let mut stream = Arc::new(RwLock::new(conn));
+    let session = Arc::new(Session {
+        username: RwLock::new(credentials.username),
+        password: RwLock::new(credentials.password),
+        registering: RwLock::new(None),
+        room: RwLock::new(DEFAULT_ROOM.into()),
+        reply_to: RwLock::new(None),
+        shutdown: RwLock::new(None),
+        quitting: AtomicBool::new(false),
+        online: AtomicBool::new(true),
+        pending: Mutex::new(VecDeque::new()),
+        output: config.output,
+        feed: config.tui.then_some(feed),
+        users,
+        features,
+    });
+    if session.features.contains(&Feature::Users) {
+        send(&stream, &session, &Message::ListUsers);
+    }
+
+    let tui = config.tui;
+    let title = match &config.tls {
+        Some(_) if matches!(endpoint, Endpoint::Tcp(_)) => format!("{} (TLS)", endpoint),
+        _ => endpoint.to_string(),
+    };
     let reader_running_clone = running.clone();
     let stream_clone = stream.clone();
+    let session_clone = session.clone();
     let reader = thread::Builder::new()
         .name("reader".into())
         .spawn(move || {
-            while reader_running_clone.load(Ordering::SeqCst) {
-                if let Ok(mut stream) = stream_clone.try_write() {
-                    match read_messages(&mut stream) {
-                        Ok(Some(msgs)) => {
-                            for msg in msgs {
-                                println!("{}", msg);
-                            }
-                        }
-                        Err(e) => {
-                            eprintln!("{:?}", e);
-                        }
-                        _ => (),
-                    }
-                }
-                thread::yield_now();
-                thread::sleep(Duration::from_millis(10));
-            }
+            read_loop(
+                &endpoint,
+                &config,
+                &stream_clone,
+                &session_clone,
+                &reader_running_clone,
+            )
         })
         .expect("Could not setup reader");
 
-    chat(&mut stream, &username, &running);
+    if tui {
+        tui::run(&mut stream, &session, &running, feed_receiver, &title);
+    } else {
+        chat(&mut stream, &session, &running, input);
+    }
 
     running.store(false, Ordering::SeqCst);
     reader.join().expect("Failed to wait for reader");
 
-    loop {
-        if let Ok(stream) = stream.try_write() {
-            stream
-                .shutdown(Shutdown::Both)
-                .expect("Failed disconnecting...");
-            break;
+    // Might be gone already if we were offline
+    let _ = stream.write().unwrap().stream_mut().shutdown();
+}
+
+/// Prints whatever the server sends until we're done, getting the
+/// connection back whenever it's lost or goes quiet for too long.
+fn read_loop(
+    endpoint: &Endpoint,
+    config: &ClientConfig,
+    stream: &RwLock<Connection>,
+    session: &Session,
+    running: &AtomicBool,
+) {
+    let mut last_heard = Instant::now();
+    let mut last_ping = Instant::now();
+
+    while running.load(Ordering::SeqCst) {
+        let result = match stream.try_write() {
+            Ok(mut stream) => read_messages(&mut stream).map(|msgs| {
+                let heard = msgs.as_ref().is_some_and(|msgs| !msgs.is_empty());
+
+                for msg in msgs.unwrap_or_default() {
+                    if let Message::Ping = msg {
+                        send_message(&mut stream, &Message::Pong).unwrap_or_else(|e| {
+                            session.complain(&format!("Failed to answer ping: {}", e))
+                        });
+                        continue;
+                    }
+
+                    update_session(session, &msg);
+                    session.show(&msg);
+                }
+
+                heard
+            }),
+            Err(_) => Ok(false),
+        };
+
+        let quiet = last_heard.elapsed();
+        let result = match result {
+            Ok(true) => {
+                last_heard = Instant::now();
+                Ok(())
+            }
+            Ok(false) if quiet >= config.heartbeat * HEARTBEAT_MISSES => {
+                Err(ServerError::TimedOut(quiet).into())
+            }
+            Ok(false) if quiet >= config.heartbeat && last_ping.elapsed() >= config.heartbeat => {
+                last_ping = Instant::now();
+                send_message(&mut stream.write().unwrap(), &Message::Ping)
+            }
+            Ok(false) => Ok(()),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Err(_) if session.quitting.load(Ordering::SeqCst) => return,
+            Err(e) => {
+                // Nothing else is coming through this connection
+                if let Some(reason) = session.shutdown.read().unwrap().as_ref() {
+                    session.say(&format!("Disconnected: {}", reason));
+                    session.exit(EXIT_SERVER_SHUTDOWN);
+                    return;
+                }
+
+                session.online.store(false, Ordering::SeqCst);
+                session.say(&format!("*** Lost connection to server: {}", e));
+                // In case it's only quiet, so the server lets go of our name
+                let _ = stream.write().unwrap().stream_mut().shutdown();
+
+                match reconnect(endpoint, config, session, running) {
+                    Some(conn) => *stream.write().unwrap() = conn,
+                    None => return,
+                }
+                resume(stream, session);
+                last_heard = Instant::now();
+            }
+            Ok(()) => (),
+        }
+
+        thread::yield_now();
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+/// Connects to `endpoint`. Only TCP connections go over TLS, Unix sockets
+/// never leave the host.
+fn connect(endpoint: &Endpoint, config: &ClientConfig) -> io::Result<Connection> {
+    let stream: Box<dyn Transport> = match endpoint {
+        Endpoint::Tcp(addr) => {
+            let socket = TcpStream::connect(addr)?;
+            socket.set_nodelay(true)?;
+
+            match config.tls.clone() {
+                Some(tls) => {
+                    let name = ServerName::IpAddress(addr.ip().into());
+                    Box::new(tls::connect(tls, name, socket)?)
+                }
+                None => Box::new(socket),
+            }
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => Box::new(UnixStream::connect(path)?),
+    };
+    setup_stream(&*stream)?;
+
+    Ok(Connection::new(stream, config.max_frame_size))
+}
+
+/// Tries to get back in with the same username, waiting longer after each
+/// failure. Returns `None` if we're asked to stop in the meantime, or gave
+/// up.
+fn reconnect(
+    endpoint: &Endpoint,
+    config: &ClientConfig,
+    session: &Session,
+    running: &AtomicBool,
+) -> Option<Connection> {
+    let credentials = Credentials {
+        username: session.username.read().unwrap().clone(),
+        password: session.password.read().unwrap().clone(),
+    };
+
+    for attempt in 0..MAX_RECONNECT_ATTEMPTS {
+        let delay = backoff(attempt);
+        let line = format!(
+            "*** Reconnecting in {:.1}s (attempt {}/{})",
+            delay.as_secs_f32(),
+            attempt + 1,
+            MAX_RECONNECT_ATTEMPTS
+        );
+        session.say(&line);
+        thread::sleep(delay);
+
+        if !running.load(Ordering::SeqCst) {
+            return None;
+        }
+
+        let result = connect(endpoint, config)
+            .map_err(ServerError::from)
+            .and_then(|mut conn| handshake(&mut conn, credentials.clone(), None).map(|_| conn));
+
+        match result {
+            Ok(conn) => return Some(conn),
+            Err(e) => session.say(&format!("*** Reconnect failed: {}", e)),
         }
     }
+
+    session.complain("Could not get back to the server, giving up");
+    session.exit(EXIT_CONNECTION_LOST);
+
+    None
+}
+
+/// Picks up where we left off after reconnecting: back in the same room, and
+/// whatever was typed in the meantime sent.
+fn resume(stream: &RwLock<Connection>, session: &Session) {
+    session.online.store(true, Ordering::SeqCst);
+    session.say("*** Reconnected");
+
+    let username = session.username.read().unwrap().clone();
+    let room = session.room.read().unwrap().clone();
+    if room != DEFAULT_ROOM {
+        send(stream, session, &Message::JoinRoom { username, room });
+    }
+
+    // Whoever came and went meanwhile never told us
+    if session.features.contains(&Feature::Users) {
+        send(stream, session, &Message::ListUsers);
+    }
+
+    let pending: Vec<Message> = session.pending.lock().unwrap().drain(..).collect();
+    for msg in pending.iter() {
+        send(stream, session, msg);
+    }
+}
+
+/// Exponential backoff with jitter, so a server coming back up isn't hit by
+/// every client at the same time.
+fn backoff(attempt: u32) -> Duration {
+    let delay = INITIAL_BACKOFF
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_BACKOFF);
+    let half = delay.as_millis() as u64 / 2;
+    let jitter = RandomState::new().build_hasher().finish() % (half + 1);
+
+    Duration::from_millis(half + jitter)
 }
 
-fn readline(pre: &str) -> String {
-    print!("{}", pre);
-    stdout().flush().unwrap();
+/// Reads a line after showing `pre`, where `output` has our own lines go.
+/// Returns `None` once stdin is done.
+fn readline(pre: &str, output: Output) -> Option<String> {
+    match output {
+        Output::Text => {
+            print!("{}", pre);
+            stdout().flush().unwrap();
+        }
+        Output::Json => eprint!("{}", pre),
+    }
 
     let mut input = String::new();
-    stdin().read_line(&mut input).unwrap();
+    if stdin().read_line(&mut input).unwrap() == 0 {
+        return None;
+    }
 
     let x: &[_] = &['\n', '\r', ' '];
-    input.trim_matches(x).into()
+    Some(input.trim_matches(x).into())
 }
 
-fn get_username() -> String {
+fn get_username(output: Output) -> String {
     loop {
-        let input = readline("Username: ").to_owned();
+        let input = readline("Username: ", output).unwrap_or_else(|| {
+            eprintln!("ERROR: No username given");
+            process::exit(1);
+        });
 
-        let input_len = input.len();
-
-        if input_len > 15 || input_len < 5 {
-            println!("Username needs to have 5-15 chars");
+        if let Err(reason) = validate_username(&input) {
+            say(output, &reason);
             continue;
         }
 
@@ -100,50 +538,457 @@ fn get_username() -> String {
     }
 }
 
-pub fn handshake(mut stream: &mut TcpStream, username: &str) {
-    // TODO: Handle invalid username errors
+fn get_password(username: &str, output: Output) -> String {
+    let prompt = format!("Password for {}: ", username);
+
+    // Without a terminal to hide it on, it's read like anything else
+    rpassword::prompt_password(&prompt)
+        .unwrap_or_else(|_| readline(&prompt, output).unwrap_or_default())
+}
 
-    println!("DEBUG: Handshaking...");
+/// Introduces ourselves to the server and asks for the username in
+/// `credentials`. With `prompt`, asks for a password when the name needs one
+/// and for another name while the server refuses it, showing our own lines
+/// the way that `Output` does. Otherwise the refusal is returned. Returns
+/// the accepted credentials and the features negotiated for this session.
+pub fn handshake(
+    stream: &mut Connection,
+    credentials: Credentials,
+    prompt: Option<Output>,
+) -> Result<(Credentials, Vec<Feature>), ServerError> {
+    let hello = Message::Hello {
+        version: PROTOCOL_VERSION,
+        features: CLIENT_FEATURES.to_vec(),
+    };
+    send_message(stream, &hello)?;
 
-    common::send_string(&mut stream, common::SUPER_SECRET_CLIENT_HANDSHAKE.into()).unwrap();
+    let features = match read_message(stream)? {
+        Message::Welcome { version, features } => {
+            if !(MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) {
+                return Err(ServerError::FailedHandshake(
+                    HandshakeError::IncompatibleVersion(version),
+                ));
+            }
+            features
+        }
+        Message::Error { reason, .. } => {
+            return Err(ServerError::FailedHandshake(HandshakeError::Rejected(
+                reason,
+            )))
+        }
+        _ => {
+            return Err(ServerError::FailedHandshake(
+                HandshakeError::UnexpectedMessage,
+            ))
+        }
+    };
 
-    let welcome = common::read_to_string(&mut stream).expect("Could not read from server");
+    let mut credentials = credentials;
 
-    if welcome != common::SUPER_SECRET_SERVER_HANDSHAKE {
-        panic!("Failed to join server")
+    loop {
+        if let Some(output) = prompt {
+            if credentials.password.is_none() && !is_guest(&credentials.username) {
+                credentials.password = Some(get_password(&credentials.username, output));
+            }
+        }
+
+        let join = Message::Join {
+            username: credentials.username.clone(),
+            password: credentials.password.clone(),
+        };
+        send_message(stream, &join)?;
+
+        match read_message(stream)? {
+            Message::Join {
+                username: accepted, ..
+            } if accepted == credentials.username => break,
+            Message::Error {
+                code:
+                    ErrorCode::InvalidUsername
+                    | ErrorCode::UsernameTaken
+                    | ErrorCode::AuthenticationFailed,
+                reason,
+            } if prompt.is_some() => {
+                let output = prompt.expect("Prompting");
+                say(output, &reason);
+                credentials = Credentials {
+                    username: get_username(output),
+                    password: None,
+                };
+            }
+            Message::Error {
+                code: ErrorCode::AuthenticationFailed,
+                reason,
+            } => {
+                return Err(ServerError::FailedHandshake(
+                    HandshakeError::AuthenticationFailed(reason),
+                ))
+            }
+            Message::Error { reason, .. } => {
+                return Err(ServerError::FailedHandshake(HandshakeError::Rejected(
+                    reason,
+                )))
+            }
+            _ => {
+                return Err(ServerError::FailedHandshake(
+                    HandshakeError::UnexpectedMessage,
+                ))
+            }
+        }
     }
 
-    println!("DEBUG: Success");
+    Ok((credentials, features))
+}
 
-    common::send_string(&mut stream, username.into()).expect("Failed to write username");
+/// Follows us around when the server confirms we joined or left a room or
+/// changed names, and remembers who to `/reply` to.
+/// Switches `room` once the server says `me` joined another one, or left it.
+pub fn follow_room(room: &mut String, me: &str, msg: &Message) {
+    match msg {
+        Message::JoinRoom {
+            username,
+            room: joined,
+        } if username == me => *room = joined.clone(),
+        Message::PartRoom {
+            username,
+            room: left,
+        } if username == me && room == left => *room = DEFAULT_ROOM.into(),
+        _ => (),
+    }
 }
 
-pub fn chat(stream: &mut Arc<RwLock<TcpStream>>, username: &str, running: &Arc<AtomicBool>) {
-    let pre = format!("{}: ", username);
-    let mut msg;
+fn update_session(session: &Session, msg: &Message) {
+    let me = session.username.read().unwrap().clone();
 
-    while running.load(Ordering::SeqCst) {
-        msg = readline(&pre);
+    follow_room(&mut session.room.write().unwrap(), &me, msg);
 
-        match msg.as_str() {
-            "/exit" => {
-                println!("Exiting...");
-                break;
+    match msg {
+        Message::Direct { from, .. } => *session.reply_to.write().unwrap() = Some(from.clone()),
+        Message::Renamed { from, to } if *from == me => {
+            *session.username.write().unwrap() = to.clone();
+
+            // Registering is the only way to get a name needing a password
+            let mut registering = session.registering.write().unwrap();
+            *session.password.write().unwrap() = match registering.take() {
+                Some((username, password)) if username == *to => Some(password),
+                _ => None,
+            };
+        }
+        Message::Shutdown { reason, .. } => {
+            *session.shutdown.write().unwrap() = Some(reason.clone())
+        }
+        _ => (),
+    }
+
+    let mut users = session.users.write().unwrap();
+    match msg {
+        Message::Users { users: online } => *users = online.clone(),
+        Message::Join { username, .. } if !users.contains(username) => {
+            users.push(username.clone());
+            users.sort();
+        }
+        Message::Goodbye { username, .. } => users.retain(|u| u != username),
+        Message::Renamed { from, to } => {
+            users.retain(|u| u != from);
+            users.push(to.clone());
+            users.sort();
+        }
+        _ => (),
+    }
+}
+
+fn print_message(msg: &Message) {
+    for line in describe(msg) {
+        match msg {
+            Message::Error { .. } => eprintln!("{}", line),
+            _ => println!("{}", line),
+        }
+    }
+}
+
+/// What `msg` looks like as text, one line at a time.
+pub fn describe(msg: &Message) -> Vec<String> {
+    match msg {
+        Message::Chat {
+            from,
+            room: Some(room),
+            text,
+        } => vec![format!("[{}] {}: {}", room, from, text)],
+        Message::Chat { from, text, .. } => vec![format!("{}: {}", from, text)],
+        Message::Direct { from, text, .. } => vec![format!("[dm] {}: {}", from, text)],
+        Message::Emote { from, room, text } => vec![format!("[{}] * {} {}", room, from, text)],
+        Message::Renamed { from, to } => vec![format!("*** {} is now known as {}", from, to)],
+        Message::JoinRoom { username, room } => vec![format!("*** {} joined {}", username, room)],
+        Message::PartRoom { username, room } => vec![format!("*** {} left {}", username, room)],
+        Message::History { room, records } if records.is_empty() => {
+            vec![format!("*** Nothing said in {} yet", room)]
+        }
+        Message::History { room, records } => {
+            let mut lines = vec![format!("*** Last {} lines in {}", records.len(), room)];
+            for record in records {
+                let time = record.timestamp.with_timezone(&Local);
+                lines.push(format!(
+                    "[{}] {}: {}",
+                    time.format("%Y-%m-%d %H:%M"),
+                    record.from,
+                    record.text
+                ));
             }
-            _ if msg.len() >= 1 => {
-                send_msg(stream, &msg);
+            lines
+        }
+        Message::SearchResults { query, records } if records.is_empty() => {
+            vec![format!("*** Nothing found for {}", query)]
+        }
+        Message::SearchResults { query, records } => {
+            let mut lines = vec![format!("*** {} lines found for {}", records.len(), query)];
+            for record in records {
+                let time = record.timestamp.with_timezone(&Local);
+                lines.push(format!(
+                    "[{}] [{}] {}: {}",
+                    time.format("%Y-%m-%d %H:%M"),
+                    record.room,
+                    record.from,
+                    record.text
+                ));
             }
-            _ => continue,
+            lines
         }
+        Message::Rooms { rooms } => rooms
+            .iter()
+            .map(|room| format!("*** {} ({} users)", room.name, room.members))
+            .collect(),
+        Message::System { text } => vec![format!("*** {}", text)],
+        Message::Shutdown {
+            reason,
+            grace: Some(grace),
+        } => vec![format!("*** {}, closing in {}s", reason, grace)],
+        Message::Shutdown { reason, .. } => vec![format!("*** {}", reason)],
+        Message::Join { username, .. } => vec![format!("*** {} joined", username)],
+        Message::Goodbye {
+            username,
+            reason: Some(reason),
+        } => vec![format!("*** {} left ({})", username, reason)],
+        Message::Goodbye { username, .. } => vec![format!("*** {} left", username)],
+        Message::Error { reason, .. } => vec![format!("ERROR: {}", reason)],
+        _ => vec![],
     }
 }
 
-pub fn send_msg(stream: &mut Arc<RwLock<TcpStream>>, msg: &str) {
-    loop {
-        if let Ok(mut stream) = stream.try_write() {
-            send_string(&mut stream, format!("{}\n", msg))
-                .expect("Failed to send message to server");
+/// Runs what's typed until we quit, read with `input` if there's a terminal
+/// to edit lines on.
+pub fn chat(
+    stream: &mut Arc<RwLock<Connection>>,
+    session: &Session,
+    running: &Arc<AtomicBool>,
+    mut input: Option<Input>,
+) {
+    let commands = Registry::new();
+
+    while running.load(Ordering::SeqCst) {
+        let prompt = format!(
+            "{} {}: ",
+            session.room.read().unwrap(),
+            session.username.read().unwrap()
+        );
+        let line = match &mut input {
+            Some(input) => input.readline(&prompt),
+            None => readline(&prompt, session.output),
+        }
+        // Nothing more to read, same as leaving
+        .unwrap_or_else(|| "/quit".into());
+
+        if let Outcome::Quit = handle_line(stream, session, &commands, &line) {
             break;
         }
     }
 }
+
+/// Runs a line typed in, either a command or something to say in the
+/// current room.
+pub fn handle_line(
+    stream: &mut Arc<RwLock<Connection>>,
+    session: &Session,
+    commands: &Registry,
+    line: &str,
+) -> Outcome {
+    // The room or our name may have changed while we were waiting for input
+    let current = session.room.read().unwrap().clone();
+    let username = session.username.read().unwrap().clone();
+
+    let text = match Line::parse(line) {
+        Line::Command(invocation) => {
+            let mut ctx = Context {
+                stream,
+                username: &username,
+                session,
+                room: current,
+                commands,
+            };
+
+            return commands.run(&mut ctx, &invocation);
+        }
+        Line::Chat(text) => text,
+    };
+
+    if !text.is_empty() {
+        send(
+            stream,
+            session,
+            &Message::Chat {
+                from: username,
+                room: Some(current),
+                text: text.into(),
+            },
+        );
+    }
+
+    Outcome::Continue
+}
+
+/// Sends `msg` right away, or keeps it until we're back online.
+pub fn send(stream: &RwLock<Connection>, session: &Session, msg: &Message) {
+    if session.online.load(Ordering::SeqCst) {
+        match send_message(&mut stream.write().unwrap(), msg) {
+            Ok(()) => return,
+            // The reader notices as well, and takes care of reconnecting
+            Err(e) => session.complain(&format!("Failed to send message: {}", e)),
+        }
+    }
+
+    session.say("*** Offline, will send once reconnected");
+    session.pending.lock().unwrap().push_back(msg.clone());
+}
+
+/// A connection to a server driven from code instead of a terminal. Unlike
+/// `join`, nothing is retried: losing the connection is up to the caller.
+pub struct Client {
+    conn: Connection,
+    username: String,
+    features: Vec<Feature>,
+    /// Messages read from the server but not handed out yet.
+    incoming: VecDeque<Message>,
+}
+
+impl Client {
+    /// Connects to `endpoint` and joins as `credentials`, failing if the
+    /// server refuses them.
+    pub fn connect(
+        endpoint: &Endpoint,
+        credentials: Credentials,
+        config: &ClientConfig,
+    ) -> Result<Self, ServerError> {
+        Client::join(connect(endpoint, config)?, credentials)
+    }
+
+    /// Joins as `credentials` over a transport that's already connected, like
+    /// one from `server::Handle::connect`.
+    pub fn over(
+        stream: Box<dyn Transport>,
+        credentials: Credentials,
+        config: &ClientConfig,
+    ) -> Result<Self, ServerError> {
+        setup_stream(&*stream)?;
+
+        Client::join(Connection::new(stream, config.max_frame_size), credentials)
+    }
+
+    fn join(mut conn: Connection, credentials: Credentials) -> Result<Self, ServerError> {
+        let (credentials, features) = handshake(&mut conn, credentials, None)?;
+
+        Ok(Client {
+            conn,
+            username: credentials.username,
+            features,
+            incoming: VecDeque::new(),
+        })
+    }
+
+    /// Our name, kept up to date when the server renames us.
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    /// What was agreed on during the handshake.
+    pub fn features(&self) -> &[Feature] {
+        &self.features
+    }
+
+    pub fn send(&mut self, msg: &Message) -> Result<(), ServerError> {
+        Ok(send_message(&mut self.conn, msg)?)
+    }
+
+    /// Says `text` in `room`.
+    pub fn chat(&mut self, room: &str, text: &str) -> Result<(), ServerError> {
+        self.send(&Message::Chat {
+            from: self.username.clone(),
+            room: Some(room.into()),
+            text: text.into(),
+        })
+    }
+
+    /// Waits up to `timeout` for the next message, or for as long as it
+    /// takes without one. Pings are answered along the way, not returned.
+    /// Fails with `ServerError::UserShutdown` once the server hung up and
+    /// everything it sent was handed out.
+    pub fn recv(&mut self, timeout: Option<Duration>) -> Result<Option<Message>, ServerError> {
+        let started = Instant::now();
+
+        loop {
+            if let Some(msg) = self.incoming.pop_front() {
+                return Ok(Some(msg));
+            }
+
+            for msg in read_messages(&mut self.conn)?.unwrap_or_default() {
+                match msg {
+                    Message::Ping => send_message(&mut self.conn, &Message::Pong)?,
+                    Message::Renamed { ref from, ref to } if *from == self.username => {
+                        self.username = to.clone();
+                        self.incoming.push_back(msg);
+                    }
+                    msg => self.incoming.push_back(msg),
+                }
+            }
+
+            if self.incoming.is_empty() && timeout.is_some_and(|t| started.elapsed() >= t) {
+                return Ok(None);
+            }
+        }
+    }
+
+    /// Everything the server sends, as it comes, until it hangs up.
+    pub fn events(&mut self) -> Events<'_> {
+        Events { client: self }
+    }
+
+    /// Says goodbye and hangs up.
+    pub fn close(mut self, reason: Option<&str>) -> Result<(), ServerError> {
+        let goodbye = Message::Goodbye {
+            username: self.username.clone(),
+            reason: reason.map(String::from),
+        };
+        send_message(&mut self.conn, &goodbye)?;
+
+        // Might be gone already
+        let _ = self.conn.stream_mut().shutdown();
+
+        Ok(())
+    }
+}
+
+/// Blocking iterator over what a `Client` receives. It ends once the server
+/// hangs up, any other error is handed out before that.
+pub struct Events<'a> {
+    client: &'a mut Client,
+}
+
+impl Iterator for Events<'_> {
+    type Item = Result<Message, ServerError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.client.recv(None) {
+            Ok(msg) => msg.map(Ok),
+            Err(ServerError::UserShutdown) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
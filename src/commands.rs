@@ -0,0 +1,380 @@
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+
+use crate::client::{send, Session};
+use crate::common::{Connection, Message};
+
+/// What the chat loop should do once a command ran.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    Continue,
+    Quit,
+}
+
+/// A line starting with `/`, split into the command name and its arguments.
+pub struct Invocation<'a> {
+    pub name: &'a str,
+    pub args: Vec<&'a str>,
+    /// Everything after the name, spacing untouched.
+    rest: &'a str,
+}
+
+impl<'a> Invocation<'a> {
+    /// Returns `None` for lines that aren't commands, including the ones
+    /// starting with `//`, which is how a chat line can start with a slash.
+    pub fn parse(line: &'a str) -> Option<Self> {
+        let body = line.trim_start().strip_prefix('/')?;
+        let name = body.split(char::is_whitespace).next()?;
+
+        if name.is_empty() || name.starts_with('/') {
+            return None;
+        }
+
+        let rest = body[name.len()..].trim_start();
+
+        Some(Invocation {
+            name,
+            args: rest.split_whitespace().collect(),
+            rest,
+        })
+    }
+
+    /// Free text following the first `n` arguments, spacing untouched.
+    pub fn text_after(&self, n: usize) -> &'a str {
+        let mut text = self.rest;
+
+        for _ in 0..n {
+            text = text
+                .trim_start_matches(|c: char| !c.is_whitespace())
+                .trim_start();
+        }
+
+        text
+    }
+}
+
+/// A line of input: either a command, or chat with the `//` escape undone.
+pub enum Line<'a> {
+    Command(Invocation<'a>),
+    Chat(&'a str),
+}
+
+impl<'a> Line<'a> {
+    pub fn parse(line: &'a str) -> Self {
+        if let Some(invocation) = Invocation::parse(line) {
+            return Line::Command(invocation);
+        }
+
+        // "//" at the start stands for a single slash
+        match line.strip_prefix('/') {
+            Some(rest) if rest.starts_with('/') => Line::Chat(rest),
+            _ => Line::Chat(line),
+        }
+    }
+}
+
+/// Everything a command can get at while it runs.
+pub struct Context<'a> {
+    pub stream: &'a mut Arc<RwLock<Connection>>,
+    pub username: &'a str,
+    pub session: &'a Session,
+    /// Room the command was typed in.
+    pub room: String,
+    pub commands: &'a Registry,
+}
+
+impl Context<'_> {
+    pub fn send(&mut self, msg: &Message) {
+        send(self.stream, self.session, msg);
+    }
+}
+
+type Handler = fn(&mut Context, &Invocation) -> Outcome;
+
+pub struct Command {
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// Arguments as shown by `/help`, like `<user> <text>`.
+    pub usage: &'static str,
+    pub about: &'static str,
+    pub min_args: usize,
+    /// `None` for commands taking free text.
+    pub max_args: Option<usize>,
+    pub run: Handler,
+}
+
+impl Command {
+    /// Whether `invocation` has as many arguments as this command takes.
+    pub fn accepts(&self, invocation: &Invocation) -> bool {
+        let given = invocation.args.len();
+        given >= self.min_args && self.max_args.is_none_or(|max| given <= max)
+    }
+}
+
+/// Commands the client handles itself. Anything else starting with `/` goes
+/// to the server as a `Message::Command`, which answers with an error if it
+/// doesn't know it either.
+pub struct Registry {
+    commands: Vec<Command>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        let mut registry = Registry { commands: vec![] };
+
+        registry.register(Command {
+            name: "help",
+            aliases: &[],
+            usage: "[command]",
+            about: "List commands, or explain one",
+            min_args: 0,
+            max_args: Some(1),
+            run: help,
+        });
+        registry.register(Command {
+            name: "quit",
+            aliases: &["exit"],
+            usage: "[reason]",
+            about: "Leave the server",
+            min_args: 0,
+            max_args: None,
+            run: quit,
+        });
+        registry.register(Command {
+            name: "clear",
+            aliases: &[],
+            usage: "",
+            about: "Clear the screen",
+            min_args: 0,
+            max_args: Some(0),
+            run: clear,
+        });
+        registry.register(Command {
+            name: "join",
+            aliases: &[],
+            usage: "<#room>",
+            about: "Enter a room and talk there",
+            min_args: 1,
+            max_args: Some(1),
+            run: join_room,
+        });
+        registry.register(Command {
+            name: "part",
+            aliases: &[],
+            usage: "[#room]",
+            about: "Leave a room, the current one by default",
+            min_args: 0,
+            max_args: Some(1),
+            run: part_room,
+        });
+        registry.register(Command {
+            name: "rooms",
+            aliases: &[],
+            usage: "",
+            about: "List rooms",
+            min_args: 0,
+            max_args: Some(0),
+            run: list_rooms,
+        });
+        registry.register(Command {
+            name: "msg",
+            aliases: &[],
+            usage: "<user> <text>",
+            about: "Send a private message",
+            min_args: 2,
+            max_args: None,
+            run: direct,
+        });
+        registry.register(Command {
+            name: "reply",
+            aliases: &[],
+            usage: "<text>",
+            about: "Answer the last private message",
+            min_args: 1,
+            max_args: None,
+            run: reply,
+        });
+        registry.register(Command {
+            name: "register",
+            aliases: &[],
+            usage: "<username> <password>",
+            about: "Claim a username, so only you can use it",
+            min_args: 2,
+            max_args: Some(2),
+            run: register,
+        });
+
+        registry
+    }
+
+    /// Adds `command`, replacing any other one with the same name.
+    pub fn register(&mut self, command: Command) {
+        self.commands.retain(|c| c.name != command.name);
+        self.commands.push(command);
+    }
+
+    /// Every name a command goes by, aliases included.
+    pub fn names(&self) -> Vec<&'static str> {
+        self.commands
+            .iter()
+            .flat_map(|c| std::iter::once(c.name).chain(c.aliases.iter().copied()))
+            .collect()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Command> {
+        self.commands
+            .iter()
+            .find(|c| c.name == name || c.aliases.contains(&name))
+    }
+
+    pub fn run(&self, ctx: &mut Context, invocation: &Invocation) -> Outcome {
+        let command = match self.find(invocation.name) {
+            Some(command) => command,
+            None => return forward(ctx, invocation),
+        };
+
+        if !command.accepts(invocation) {
+            let usage = format!("/{} {}", command.name, command.usage);
+            ctx.session.say(&format!("Usage: {}", usage.trim_end()));
+            return Outcome::Continue;
+        }
+
+        (command.run)(ctx, invocation)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What `/join <room>` sends.
+pub fn join_message(username: &str, invocation: &Invocation) -> Message {
+    Message::JoinRoom {
+        username: username.into(),
+        room: invocation.args[0].into(),
+    }
+}
+
+/// What `/msg <user> <text>` sends.
+pub fn direct_message(username: &str, invocation: &Invocation) -> Message {
+    Message::Direct {
+        from: username.into(),
+        to: invocation.args[0].into(),
+        text: invocation.text_after(1).into(),
+    }
+}
+
+/// What a command the server handles sends, typed in `room`.
+pub fn forward_message(invocation: &Invocation, room: &str) -> Message {
+    Message::Command {
+        name: invocation.name.into(),
+        args: invocation.args.iter().map(|&a| a.into()).collect(),
+        room: Some(room.into()),
+    }
+}
+
+fn forward(ctx: &mut Context, invocation: &Invocation) -> Outcome {
+    ctx.send(&forward_message(invocation, &ctx.room));
+
+    Outcome::Continue
+}
+
+fn help(ctx: &mut Context, invocation: &Invocation) -> Outcome {
+    let session = ctx.session;
+    let describe = |c: &Command| {
+        let usage = format!("/{} {}", c.name, c.usage);
+        session.say(&format!("{:<20} {}", usage.trim_end(), c.about));
+    };
+
+    match invocation.args.first() {
+        Some(name) => match ctx.commands.find(name.trim_start_matches('/')) {
+            Some(command) => describe(command),
+            None => session.say(&format!(
+                "No local command /{}",
+                name.trim_start_matches('/')
+            )),
+        },
+        None => {
+            ctx.commands.commands.iter().for_each(describe);
+            session.say("Other commands are handled by the server");
+        }
+    }
+
+    Outcome::Continue
+}
+
+fn quit(ctx: &mut Context, invocation: &Invocation) -> Outcome {
+    let reason = invocation.text_after(0);
+
+    ctx.session.say("Exiting...");
+    ctx.session.quitting.store(true, Ordering::SeqCst);
+    ctx.send(&Message::Goodbye {
+        username: ctx.username.into(),
+        reason: (!reason.is_empty()).then(|| reason.into()),
+    });
+
+    Outcome::Quit
+}
+
+fn clear(ctx: &mut Context, _: &Invocation) -> Outcome {
+    ctx.session.clear();
+
+    Outcome::Continue
+}
+
+fn join_room(ctx: &mut Context, invocation: &Invocation) -> Outcome {
+    ctx.send(&join_message(ctx.username, invocation));
+
+    Outcome::Continue
+}
+
+fn part_room(ctx: &mut Context, invocation: &Invocation) -> Outcome {
+    let room = invocation
+        .args
+        .first()
+        .map_or_else(|| ctx.room.clone(), |&r| r.into());
+
+    ctx.send(&Message::PartRoom {
+        username: ctx.username.into(),
+        room,
+    });
+
+    Outcome::Continue
+}
+
+fn list_rooms(ctx: &mut Context, _: &Invocation) -> Outcome {
+    ctx.send(&Message::ListRooms);
+
+    Outcome::Continue
+}
+
+fn direct(ctx: &mut Context, invocation: &Invocation) -> Outcome {
+    ctx.send(&direct_message(ctx.username, invocation));
+
+    Outcome::Continue
+}
+
+fn register(ctx: &mut Context, invocation: &Invocation) -> Outcome {
+    // Kept so we can log back in once the server switches us over
+    *ctx.session.registering.write().unwrap() =
+        Some((invocation.args[0].into(), invocation.args[1].into()));
+
+    forward(ctx, invocation)
+}
+
+fn reply(ctx: &mut Context, invocation: &Invocation) -> Outcome {
+    let to = ctx.session.reply_to.read().unwrap().clone();
+
+    match to {
+        Some(to) => ctx.send(&Message::Direct {
+            from: ctx.username.into(),
+            to,
+            text: invocation.text_after(0).into(),
+        }),
+        None => ctx.session.say("Nobody to reply to yet"),
+    }
+
+    Outcome::Continue
+}
@@ -1,63 +1,741 @@
+use std::collections::VecDeque;
 use std::error::Error;
 use std::fmt::Display;
-use std::io::{self, ErrorKind, Read, Write};
-use std::net::TcpStream;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
 use std::string::String;
 use std::sync::*;
 use std::time::Duration;
 
-pub const SUPER_SECRET_CLIENT_HANDSHAKE: &'static str = "Hello!";
-pub const SUPER_SECRET_SERVER_HANDSHAKE: &'static str = "Welcome!";
+use chrono::{DateTime, Utc};
+use mio::Token;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 
-pub fn setup_stream(stream: &TcpStream) -> io::Result<()> {
-    stream.set_nodelay(true)?;
-    stream.set_read_timeout(Some(Duration::from_millis(1)))?;
-    stream.set_write_timeout(Some(Duration::from_secs(1)))?;
+use crate::transport::{Evented, Transport};
+
+/// Biggest payload a single frame may carry unless configured otherwise.
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 64 * 1024;
+
+/// How many frames may wait for a slow peer unless configured otherwise.
+pub const DEFAULT_MAX_QUEUE: usize = 1024;
+
+/// How often a quiet peer gets pinged unless configured otherwise.
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(15);
+
+/// How long the server waits on a silent user before dropping it unless
+/// configured otherwise.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(45);
+
+/// How many past lines are shown to a user that just joined unless configured
+/// otherwise.
+pub const DEFAULT_HISTORY_DEPTH: usize = 20;
+
+/// Most lines `/history` hands out at once.
+pub const MAX_HISTORY_REQUEST: usize = 500;
+
+/// Most lines `/search` hands out at once.
+pub const MAX_SEARCH_RESULTS: usize = 50;
+
+/// Every frame starts with its payload length as a big-endian `u32`.
+const FRAME_HEADER_LEN: usize = 4;
+
+/// Most bytes `read_messages` takes from a stream in one call, so a peer that
+/// never stops sending can't keep the server from everyone else.
+const MAX_READ_PER_CALL: usize = 64 * 1024;
+
+/// Version of the protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Oldest protocol version this build still knows how to talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+pub fn setup_stream(stream: &dyn Transport) -> io::Result<()> {
+    stream.set_timeouts(Some(Duration::from_millis(1)), Some(Duration::from_secs(1)))
+}
+
+/// Prefixes `payload` with its length so the other side can find where it ends.
+pub fn encode_frame(payload: &[u8], max_frame_size: usize) -> Result<Vec<u8>, ServerError> {
+    if payload.len() > max_frame_size {
+        return Err(ServerError::FrameTooLarge(payload.len()));
+    }
+
+    let mut frame = Vec::with_capacity(FRAME_HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+    frame.extend_from_slice(payload);
+
+    Ok(frame)
+}
+
+/// Reassembles frames out of whatever chunks the socket hands us.
+///
+/// TCP doesn't care about our message boundaries, so a read can return half a
+/// frame or several of them at once. Bytes are kept here until a whole frame
+/// is available, up to a couple of frames' worth, which is plenty as long as
+/// frames are taken out after every read.
+#[derive(Debug)]
+pub struct FrameBuffer {
+    buf: Vec<u8>,
+    /// Where the frames that weren't taken out yet start.
+    start: usize,
+    max_frame_size: usize,
+}
+
+impl FrameBuffer {
+    pub fn new(max_frame_size: usize) -> Self {
+        FrameBuffer {
+            buf: Vec::new(),
+            start: 0,
+            max_frame_size,
+        }
+    }
+
+    /// How many more bytes can be buffered.
+    pub fn room(&self) -> usize {
+        let capacity = 2 * (FRAME_HEADER_LEN + self.max_frame_size);
+
+        capacity.saturating_sub(self.buf.len() - self.start)
+    }
+
+    /// Adds what was read. Fails with `ServerError::BufferFull` if there's
+    /// no `room` for it.
+    pub fn extend(&mut self, data: &[u8]) -> Result<(), ServerError> {
+        if data.len() > self.room() {
+            return Err(ServerError::BufferFull);
+        }
+
+        // Frames taken out since last time go all at once
+        self.buf.drain(..self.start);
+        self.start = 0;
+        self.buf.extend_from_slice(data);
+
+        Ok(())
+    }
+
+    /// Pops the next complete frame payload, if there is one.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, ServerError> {
+        let buffered = &self.buf[self.start..];
+        if buffered.len() < FRAME_HEADER_LEN {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        header.copy_from_slice(&buffered[..FRAME_HEADER_LEN]);
+        let len = u32::from_be_bytes(header) as usize;
+
+        if len > self.max_frame_size {
+            return Err(ServerError::FrameTooLarge(len));
+        }
+
+        if buffered.len() < FRAME_HEADER_LEN + len {
+            return Ok(None);
+        }
+
+        let payload = buffered[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len].to_vec();
+        self.start += FRAME_HEADER_LEN + len;
+
+        Ok(Some(payload))
+    }
+}
+
+pub const MIN_USERNAME_LEN: usize = 5;
+pub const MAX_USERNAME_LEN: usize = 15;
+
+/// Names anyone can take without logging in start with this. Every other name
+/// needs a password, so it has to be registered first.
+pub const GUEST_PREFIX: &str = "guest-";
+
+pub const MIN_PASSWORD_LEN: usize = 8;
+
+pub fn is_guest(name: &str) -> bool {
+    name.starts_with(GUEST_PREFIX)
+}
+
+/// Checks the rules every username has to follow. The client runs it to save
+/// a round trip, the server runs it because it can't trust the client.
+pub fn validate_username(name: &str) -> Result<(), String> {
+    let len = name.chars().count();
+
+    if !(MIN_USERNAME_LEN..=MAX_USERNAME_LEN).contains(&len) {
+        return Err(format!(
+            "Username needs to have {}-{} chars",
+            MIN_USERNAME_LEN, MAX_USERNAME_LEN
+        ));
+    }
+
+    if let Some(c) = name
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+    {
+        return Err(format!(
+            "Username can't contain {:?}, only letters, digits, '_' and '-'",
+            c
+        ));
+    }
 
     Ok(())
 }
 
-pub fn send_string(stream: &mut TcpStream, msg: String) -> Result<(), Box<dyn Error>> {
-    stream.write_all(msg.as_bytes())?;
-    stream.flush()?;
+/// Room everyone is put in when they join.
+pub const DEFAULT_ROOM: &str = "#general";
+
+pub const MAX_ROOM_LEN: usize = 32;
+
+/// Room names look like `#name`, using the same characters as usernames.
+pub fn validate_room(name: &str) -> Result<(), String> {
+    let rest = match name.strip_prefix('#') {
+        Some(rest) if !rest.is_empty() => rest,
+        _ => return Err(format!("Room names start with '#', like {}", DEFAULT_ROOM)),
+    };
+
+    if name.chars().count() > MAX_ROOM_LEN {
+        return Err(format!("Room names can have up to {} chars", MAX_ROOM_LEN));
+    }
+
+    if let Some(c) = rest
+        .chars()
+        .find(|c| !(c.is_ascii_alphanumeric() || *c == '_' || *c == '-'))
+    {
+        return Err(format!(
+            "Room name can't contain {:?}, only letters, digits, '_' and '-'",
+            c
+        ));
+    }
+
     Ok(())
 }
 
-pub fn read_to_string(stream: &mut TcpStream) -> Result<String, Box<dyn Error>> {
-    let mut buf = [0u8; 1024];
+/// Optional protocol extensions. Both sides list what they support during the
+/// handshake and only the ones present on both lists are used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Feature {
+    History,
+    Rooms,
+    /// Asking for who's online with `ListUsers`.
+    Users,
+}
 
-    let read: usize;
+/// Returns the features both peers support.
+pub fn negotiate_features(ours: &[Feature], theirs: &[Feature]) -> Vec<Feature> {
+    ours.iter()
+        .filter(|f| theirs.contains(f))
+        .cloned()
+        .collect()
+}
 
-    loop {
-        match stream.read(&mut buf) {
-            Ok(n) => {
-                if n == 0 {
-                    return Err(Box::new(ServerError::UserShutdown));
+/// Picks the version both peers can speak, if there's one.
+pub fn negotiate_version(theirs: u32) -> Option<u32> {
+    if theirs < MIN_PROTOCOL_VERSION {
+        return None;
+    }
+
+    Some(theirs.min(PROTOCOL_VERSION))
+}
+
+/// Hands every line of the JSON lines file at `path` to `keep`, if there's
+/// a file yet, and opens it to append more. Lines that don't parse are
+/// skipped.
+pub fn open_jsonl<T: DeserializeOwned>(path: &Path, mut keep: impl FnMut(T)) -> io::Result<File> {
+    if path.exists() {
+        for (number, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            match serde_json::from_str(&line?) {
+                Ok(value) => keep(value),
+                Err(e) => eprintln!(
+                    "ERROR: Skipping line {} of {}: {}",
+                    number + 1,
+                    path.display(),
+                    e
+                ),
+            }
+        }
+    }
+
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Adds `value` to a file opened by `open_jsonl`.
+pub fn append_jsonl<T: Serialize>(file: &mut File, value: &T) -> io::Result<()> {
+    let mut line = serde_json::to_string(value)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())
+}
+
+/// Tells the peer what kind of problem an `Error` message is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    IncompatibleVersion,
+    UnexpectedMessage,
+    InvalidUsername,
+    UsernameTaken,
+    AuthenticationFailed,
+    InvalidRoom,
+    NotInRoom,
+    NoSuchUser,
+    UnknownCommand,
+    CommandFailed,
+    /// Too long to pass on once the server filled in who it's from.
+    MessageTooLarge,
+}
+
+/// What `/rooms` tells about each room.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RoomInfo {
+    pub name: String,
+    pub members: usize,
+}
+
+/// A line of chat as kept in the history.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HistoryRecord {
+    pub timestamp: DateTime<Utc>,
+    pub from: String,
+    pub room: String,
+    pub text: String,
+}
+
+/// Everything that can travel between a client and the server.
+///
+/// Each message is serialized into a single frame, so there's no need for
+/// separators or escaping inside the text fields.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// First thing a client says after connecting.
+    Hello {
+        version: u32,
+        #[serde(default)]
+        features: Vec<Feature>,
+    },
+    /// The server's answer to `Hello`, with the version and features that
+    /// will be used for the rest of the session.
+    Welcome {
+        version: u32,
+        #[serde(default)]
+        features: Vec<Feature>,
+    },
+    /// Sent by the client to pick a username. The server echoes it back to
+    /// accept the name, and also uses it to announce that someone joined.
+    /// Registered names need their password, guest names don't.
+    Join {
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        password: Option<String>,
+    },
+    /// A line of chat. The server always overwrites `from` with the name of
+    /// the user that sent it, so nobody can speak for someone else.
+    ///
+    /// Lines without a room go to `DEFAULT_ROOM`, which is all clients that
+    /// don't know about rooms ever see.
+    Chat {
+        #[serde(default)]
+        from: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+        text: String,
+    },
+    /// Asks to enter a room, creating it if needed. The server sends it back
+    /// to everyone in the room, newcomer included, with `username` filled in.
+    JoinRoom {
+        #[serde(default)]
+        username: String,
+        room: String,
+    },
+    /// Asks to leave a room. Echoed like `JoinRoom`.
+    PartRoom {
+        #[serde(default)]
+        username: String,
+        room: String,
+    },
+    /// A private line for a single user. Like `Chat`, `from` is filled in by
+    /// the server.
+    Direct {
+        #[serde(default)]
+        from: String,
+        to: String,
+        text: String,
+    },
+    /// A slash command the client doesn't handle itself, like `/who`.
+    Command {
+        name: String,
+        #[serde(default)]
+        args: Vec<String>,
+        /// Room it was typed in, for commands like `/me` and `/topic`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        room: Option<String>,
+    },
+    /// Someone doing something, from `/me`.
+    Emote {
+        from: String,
+        room: String,
+        text: String,
+    },
+    /// Someone changed their username with `/nick`.
+    Renamed {
+        from: String,
+        to: String,
+    },
+    /// Past lines of a room, oldest first. Only sent to clients that
+    /// negotiated `Feature::History`.
+    History {
+        room: String,
+        records: Vec<HistoryRecord>,
+    },
+    /// Lines matching a `/search`, oldest first.
+    SearchResults {
+        query: String,
+        records: Vec<HistoryRecord>,
+    },
+    ListRooms,
+    Rooms {
+        rooms: Vec<RoomInfo>,
+    },
+    /// Asks who's online. Only for clients that negotiated `Feature::Users`.
+    ListUsers,
+    /// Everyone online, sorted by name.
+    Users {
+        users: Vec<String>,
+    },
+    /// A notice from the server itself.
+    System {
+        text: String,
+    },
+    /// The server is going away. Connections get closed once `grace`
+    /// seconds are up, or right after this if there's no grace period.
+    Shutdown {
+        reason: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        grace: Option<u64>,
+    },
+    Error {
+        code: ErrorCode,
+        reason: String,
+    },
+    Ping,
+    Pong,
+    /// Sent by a client that's leaving, and by the server to announce it.
+    Goodbye {
+        #[serde(default)]
+        username: String,
+        #[serde(default)]
+        reason: Option<String>,
+    },
+}
+
+impl Message {
+    pub fn encode(&self) -> Result<String, ServerError> {
+        serde_json::to_string(self).map_err(|e| ServerError::InvalidMessage(e.to_string()))
+    }
+
+    pub fn decode(data: &str) -> Result<Self, ServerError> {
+        serde_json::from_str(data).map_err(|e| ServerError::InvalidMessage(e.to_string()))
+    }
+}
+
+/// What to do with a peer that doesn't read as fast as we write to it, once
+/// its outbound queue is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverflowPolicy {
+    /// Forget the oldest message that hasn't started going out yet.
+    DropOldest,
+    /// Give up on the peer.
+    Disconnect,
+}
+
+impl FromStr for OverflowPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(OverflowPolicy::DropOldest),
+            "disconnect" => Ok(OverflowPolicy::Disconnect),
+            _ => Err(format!(
+                "Unknown overflow policy {:?}, use drop-oldest or disconnect",
+                s
+            )),
+        }
+    }
+}
+
+/// A stream together with the reassembly buffer for the frames coming from it
+/// and the queue of frames we still owe it.
+#[derive(Debug)]
+pub struct Connection<S = Box<dyn Transport>> {
+    stream: S,
+    frames: FrameBuffer,
+    outbound: VecDeque<Vec<u8>>,
+    /// How much of the frame at the front of `outbound` was already written.
+    written: usize,
+    max_queue: usize,
+    max_frame_size: usize,
+    closed: bool,
+    /// Set when `read_messages` stopped before the stream ran dry.
+    unread: bool,
+}
+
+impl<S: Read + Write> Connection<S> {
+    pub fn new(stream: S, max_frame_size: usize) -> Self {
+        Connection {
+            stream,
+            frames: FrameBuffer::new(max_frame_size),
+            outbound: VecDeque::new(),
+            written: 0,
+            max_queue: DEFAULT_MAX_QUEUE,
+            max_frame_size,
+            closed: false,
+            unread: false,
+        }
+    }
+
+    pub fn with_max_queue(mut self, max_queue: usize) -> Self {
+        self.max_queue = max_queue;
+        self
+    }
+
+    pub fn stream_mut(&mut self) -> &mut S {
+        &mut self.stream
+    }
+
+    /// Whether the peer has closed its side of the stream.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Whether the last `read_messages` left data behind. The stream won't
+    /// get ready again for it, so it's up to the caller to come back.
+    pub fn has_unread(&self) -> bool {
+        self.unread
+    }
+
+    /// Appends `msg` to the outbound queue. Nothing touches the socket until
+    /// `flush_pending` is called. Fails with `ServerError::QueueFull` when
+    /// the queue already holds as many frames as it may.
+    pub fn queue_message(&mut self, msg: &Message) -> Result<(), ServerError> {
+        if self.outbound.len() >= self.max_queue {
+            return Err(ServerError::QueueFull);
+        }
+
+        let frame = encode_frame(msg.encode()?.as_bytes(), self.max_frame_size)?;
+        self.outbound.push_back(frame);
+
+        Ok(())
+    }
+
+    /// Forgets the oldest queued frame that hasn't been partially written,
+    /// since cutting one short would corrupt the stream. Returns whether a
+    /// frame was dropped.
+    pub fn drop_oldest(&mut self) -> bool {
+        let index = if self.written > 0 { 1 } else { 0 };
+
+        self.outbound.remove(index).is_some()
+    }
+
+    /// Writes as many queued frames as the stream takes without blocking.
+    /// Returns whether everything went out.
+    pub fn flush_pending(&mut self) -> io::Result<bool> {
+        while let Some(frame) = self.outbound.front() {
+            match self.stream.write(&frame[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    self.written += n;
+
+                    if self.written == frame.len() {
+                        self.outbound.pop_front();
+                        self.written = 0;
+                    }
                 }
-                read = n;
-                break;
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) => return Err(e),
             }
-            Err(e) if e.kind() == ErrorKind::WouldBlock => continue,
+        }
+
+        match self.stream.flush() {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Does a single read from the socket into the frame buffer, no more
+    /// than it has room for.
+    fn fill(&mut self) -> io::Result<usize> {
+        let mut buf = [0u8; 4096];
+        let room = self.frames.room().min(buf.len());
+        if room == 0 {
+            return Err(io::Error::other(ServerError::BufferFull));
+        }
+
+        let read = self.stream.read(&mut buf[..room])?;
+        self.frames.extend(&buf[..read]).map_err(io::Error::other)?;
+
+        if read == 0 {
+            self.closed = true;
+        }
+
+        Ok(read)
+    }
+}
+
+fn frame_to_string(frame: Vec<u8>) -> Result<String, Box<dyn Error + Send + Sync>> {
+    Ok(String::from_utf8(frame)?)
+}
+
+pub fn send_string<S: Read + Write>(
+    conn: &mut Connection<S>,
+    msg: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let frame = encode_frame(msg.as_bytes(), conn.max_frame_size)?;
+
+    conn.stream.write_all(&frame)?;
+    conn.stream.flush()?;
+    Ok(())
+}
+
+/// Blocks until a whole frame has arrived and returns it.
+pub fn read_to_string<S: Read + Write>(
+    conn: &mut Connection<S>,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    loop {
+        if let Some(frame) = conn.frames.next_frame()? {
+            return frame_to_string(frame);
+        }
+
+        match conn.fill() {
+            Ok(0) => return Err(Box::new(ServerError::UserShutdown)),
+            Ok(_) => continue,
+            Err(e) if is_transient(&e) => continue,
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+}
+
+pub fn send_message<S: Read + Write>(
+    conn: &mut Connection<S>,
+    msg: &Message,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    send_string(conn, msg.encode()?)
+}
+
+/// Blocks until a whole message has arrived and decodes it.
+pub fn read_message<S: Read + Write>(
+    conn: &mut Connection<S>,
+) -> Result<Message, Box<dyn Error + Send + Sync>> {
+    let data = read_to_string(conn)?;
+
+    Ok(Message::decode(&data)?)
+}
+
+/// Reads what the stream has for us right now, up to `MAX_READ_PER_CALL`
+/// bytes, and returns the messages that are complete so far, without waiting
+/// for more. Check `has_unread` for whether anything was left behind.
+///
+/// Messages that arrived right before the peer hung up are still returned.
+/// The hang up is reported as `ServerError::UserShutdown` once there's
+/// nothing left to read, and can be checked earlier with `is_closed`.
+pub fn read_messages<S: Read + Write>(
+    conn: &mut Connection<S>,
+) -> Result<Option<Vec<Message>>, Box<dyn Error + Send + Sync>> {
+    let mut messages = Vec::new();
+    let mut read = 0;
+    conn.unread = false;
+
+    loop {
+        // Taken out after every read, so there's always room for the next
+        while let Some(frame) = conn.frames.next_frame()? {
+            messages.push(Message::decode(&frame_to_string(frame)?)?);
+        }
+
+        if conn.closed {
+            break;
+        }
+        if read >= MAX_READ_PER_CALL {
+            conn.unread = true;
+            break;
+        }
+
+        match conn.fill() {
+            Ok(n) => read += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if is_transient(&e) => break,
             Err(e) => return Err(Box::new(e)),
         }
     }
 
-    let msg = String::from_utf8((buf[..read]).to_vec())
-        .expect("Failed to parse string received from client");
+    if messages.is_empty() {
+        if conn.closed {
+            return Err(Box::new(ServerError::UserShutdown));
+        }
+
+        return Ok(None);
+    }
+
+    Ok(Some(messages))
+}
+
+fn is_transient(e: &io::Error) -> bool {
+    let kind = e.kind();
+
+    kind == ErrorKind::TimedOut || kind == ErrorKind::Interrupted || kind == ErrorKind::WouldBlock
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    IncompatibleVersion(u32),
+    UnexpectedMessage,
+    InvalidUsername(String),
+    /// Wrong password, or a registered name asked for without one.
+    AuthenticationFailed(String),
+    Rejected(String),
+}
 
-    Ok(msg)
+impl Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::IncompatibleVersion(v) => write!(
+                f,
+                "Protocol version {} is not supported (supported: {}-{})",
+                v, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeError::UnexpectedMessage => write!(f, "Unexpected message"),
+            HandshakeError::InvalidUsername(reason) => write!(f, "Invalid username: {}", reason),
+            HandshakeError::AuthenticationFailed(reason) => {
+                write!(f, "Authentication failed: {}", reason)
+            }
+            HandshakeError::Rejected(reason) => write!(f, "{}", reason),
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum ServerError {
-    FailedHandshake,
+    FailedHandshake(HandshakeError),
     UserShutdown,
-    Other(Box<dyn Error>),
+    FrameTooLarge(usize),
+    QueueFull,
+    /// More came in than could be buffered before frames were taken out.
+    BufferFull,
+    /// Nothing heard from the peer for this long, pings included.
+    TimedOut(Duration),
+    InvalidMessage(String),
+    Other(Box<dyn Error + Send + Sync>),
 }
 
 impl Display for ServerError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Server error")
+        match self {
+            ServerError::FailedHandshake(e) => write!(f, "Handshake failed: {}", e),
+            ServerError::UserShutdown => write!(f, "Connection closed by peer"),
+            ServerError::FrameTooLarge(size) => write!(f, "Frame of {} bytes is too large", size),
+            ServerError::QueueFull => write!(f, "Outbound queue is full"),
+            ServerError::BufferFull => write!(f, "Inbound buffer is full"),
+            ServerError::TimedOut(idle) => write!(f, "Nothing heard for {:?}", idle),
+            ServerError::InvalidMessage(e) => write!(f, "Invalid message: {}", e),
+            ServerError::Other(e) => write!(f, "Server error: {}", e),
+        }
     }
 }
 
@@ -69,9 +747,13 @@ impl From<std::io::Error> for ServerError {
     }
 }
 
-impl From<Box<dyn Error>> for ServerError {
-    fn from(e: Box<dyn Error>) -> Self {
-        ServerError::Other(e)
+impl From<Box<dyn Error + Send + Sync>> for ServerError {
+    fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+        // Don't bury our own errors, callers may want to tell them apart
+        match e.downcast::<ServerError>() {
+            Ok(e) => *e,
+            Err(e) => ServerError::Other(e),
+        }
     }
 }
 
@@ -82,47 +764,155 @@ impl From<mpsc::SendError<Action>> for ServerError {
 }
 
 pub enum Action {
-    Goodbye(String),
+    /// A user leaving on purpose, with whatever reason they gave.
+    Goodbye(String, Option<String>),
     Dropped(String),
-    Broadcast { username: String, message: String },
-    Shutdown,
-    NewUser { username: String, stream: TcpStream },
+    Broadcast {
+        username: String,
+        room: String,
+        message: String,
+    },
+    Direct {
+        from: String,
+        to: String,
+        message: String,
+    },
+    /// Reserves `username` for `from`, who then goes by it.
+    Register {
+        from: String,
+        username: String,
+        password: String,
+    },
+    /// `password` hashed for `Register`, done off the event loop.
+    Hashed {
+        from: String,
+        username: String,
+        hash: Result<String, String>,
+    },
+    /// Whether the password `token` joined as `username` with was right,
+    /// checked off the event loop.
+    Verified {
+        token: Token,
+        username: String,
+        valid: bool,
+    },
+    Rename {
+        from: String,
+        to: String,
+    },
+    Emote {
+        username: String,
+        room: String,
+        message: String,
+    },
+    SetTopic {
+        username: String,
+        room: String,
+        topic: String,
+    },
+    EnterRoom {
+        username: String,
+        room: String,
+    },
+    LeaveRoom {
+        username: String,
+        room: String,
+    },
+    Shutdown {
+        reason: String,
+    },
+    NewUser {
+        username: String,
+    },
+    /// A connection that didn't come through a listener, like one end of a
+    /// `transport::pair`.
+    Connect(Box<dyn Evented>),
 }
 
-pub fn read_messages(stream: &mut TcpStream) -> Result<Option<Vec<String>>, Box<dyn Error>> {
-    if let Ok(Some(e)) = stream.take_error() {
-        return Err(Box::new(e));
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 64;
+
+    #[test]
+    fn frames_start_with_their_length() {
+        let frame = encode_frame(b"hello", MAX).unwrap();
+        assert_eq!(frame, b"\0\0\0\x05hello");
+
+        assert!(matches!(
+            encode_frame(&[b'x'; MAX + 1], MAX),
+            Err(ServerError::FrameTooLarge(len)) if len == MAX + 1
+        ));
     }
 
-    let mut buf = [0; 5];
-    let has_data = match stream.peek(&mut buf) {
-        Ok(read) if read > 0 => true,
-        Err(e) => {
-            let kind = e.kind();
+    #[test]
+    fn split_headers_wait_for_the_rest() {
+        let frame = encode_frame(b"hello", MAX).unwrap();
+        let mut frames = FrameBuffer::new(MAX);
 
-            if kind == ErrorKind::TimedOut
-                || kind == ErrorKind::Interrupted
-                || kind == ErrorKind::WouldBlock
-            {
-                return Ok(None);
-            }
+        frames.extend(&frame[..2]).unwrap();
+        assert_eq!(frames.next_frame().unwrap(), None);
+        frames.extend(&frame[2..]).unwrap();
+        assert_eq!(frames.next_frame().unwrap(), Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn frames_can_span_reads() {
+        let frame = encode_frame(b"hello world", MAX).unwrap();
+        let mut frames = FrameBuffer::new(MAX);
 
-            return Err(Box::new(e));
+        for chunk in frame[..frame.len() - 1].chunks(3) {
+            frames.extend(chunk).unwrap();
+            assert_eq!(frames.next_frame().unwrap(), None);
         }
-        _ => return Ok(None),
-    };
+        frames.extend(&frame[frame.len() - 1..]).unwrap();
+        assert_eq!(frames.next_frame().unwrap(), Some(b"hello world".to_vec()));
+        assert_eq!(frames.next_frame().unwrap(), None);
+    }
 
-    if !has_data {
-        return Ok(None);
+    #[test]
+    fn one_read_can_hold_several_frames() {
+        let mut data = encode_frame(b"one", MAX).unwrap();
+        data.extend(encode_frame(b"", MAX).unwrap());
+        data.extend(encode_frame(b"three", MAX).unwrap());
+        // And the start of one more
+        data.extend(&encode_frame(b"four", MAX).unwrap()[..6]);
+
+        let mut frames = FrameBuffer::new(MAX);
+        frames.extend(&data).unwrap();
+        assert_eq!(frames.next_frame().unwrap(), Some(b"one".to_vec()));
+        assert_eq!(frames.next_frame().unwrap(), Some(vec![]));
+        assert_eq!(frames.next_frame().unwrap(), Some(b"three".to_vec()));
+        assert_eq!(frames.next_frame().unwrap(), None);
     }
 
-    let data = read_to_string(stream)?;
+    #[test]
+    fn oversize_headers_are_refused_before_the_payload() {
+        let mut frames = FrameBuffer::new(MAX);
 
-    let messages = data
-        .split('\n')
-        .filter(|s| s.len() > 0)
-        .map(|s| s.to_string())
-        .collect();
+        frames.extend(&(MAX as u32 + 1).to_be_bytes()).unwrap();
+        assert!(matches!(
+            frames.next_frame(),
+            Err(ServerError::FrameTooLarge(len)) if len == MAX + 1
+        ));
+    }
 
-    Ok(Some(messages))
-}
\ No newline at end of file
+    #[test]
+    fn only_a_couple_of_frames_are_buffered() {
+        let frame = encode_frame(&[b'x'; MAX], MAX).unwrap();
+        let mut frames = FrameBuffer::new(MAX);
+
+        frames.extend(&frame).unwrap();
+        frames.extend(&frame).unwrap();
+        assert_eq!(frames.room(), 0);
+        assert!(matches!(frames.extend(b"x"), Err(ServerError::BufferFull)));
+
+        // Taking one out makes room for the next
+        assert_eq!(frames.next_frame().unwrap(), Some(vec![b'x'; MAX]));
+        frames.extend(&frame).unwrap();
+        assert_eq!(frames.next_frame().unwrap(), Some(vec![b'x'; MAX]));
+        assert_eq!(frames.next_frame().unwrap(), Some(vec![b'x'; MAX]));
+        assert_eq!(frames.next_frame().unwrap(), None);
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use crate::accounts::Accounts;
+use crate::common::{
+    is_guest, validate_username, Action, Feature, Message, GUEST_PREFIX, MAX_HISTORY_REQUEST,
+    MAX_SEARCH_RESULTS,
+};
+use crate::history::{fit_records, History, Query};
+use crate::server::Room;
+
+/// What a command handler gets to look at, and where it leaves its answers.
+pub struct CommandContext<'a> {
+    /// Who typed the command.
+    pub caller: &'a str,
+    /// What the caller's client negotiated.
+    pub features: &'a [Feature],
+    /// Room the command was typed in.
+    pub room: &'a str,
+    pub args: &'a [String],
+    /// Everyone that's joined, sorted.
+    pub users: Vec<&'a str>,
+    pub rooms: &'a HashMap<String, Room>,
+    pub history: &'a History,
+    pub accounts: &'a Accounts,
+    pub started: Instant,
+    /// Largest reply that can be sent.
+    pub max_frame_size: usize,
+    pub actions: &'a Sender<Action>,
+    /// Sent to the caller once the handler is done.
+    pub replies: Vec<Message>,
+}
+
+impl CommandContext<'_> {
+    /// Queues `msg` to be sent back to the caller once the handler is done.
+    pub fn reply(&mut self, msg: Message) {
+        self.replies.push(msg);
+    }
+
+    /// Replies with a server notice.
+    pub fn say(&mut self, text: String) {
+        self.reply(Message::System { text });
+    }
+
+    /// Hands an action to the event loop, like anything else would.
+    pub fn emit(&self, action: Action) -> Result<(), String> {
+        self.actions
+            .send(action)
+            .map_err(|e| format!("Server is going away: {}", e))
+    }
+
+    /// Arguments joined back into a line of text.
+    pub fn text(&self) -> String {
+        self.args.join(" ")
+    }
+}
+
+/// Handlers return a reason on failure, which the caller gets as an error.
+pub type Handler = fn(&mut CommandContext) -> Result<(), String>;
+
+/// Maps command names to their handlers.
+pub struct Dispatcher {
+    handlers: HashMap<&'static str, Handler>,
+}
+
+impl Dispatcher {
+    /// A dispatcher without any commands.
+    pub fn empty() -> Self {
+        Dispatcher {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /// Adds `handler` as `/name`, replacing the one that was there.
+    pub fn register(&mut self, name: &'static str, handler: Handler) {
+        self.handlers.insert(name, handler);
+    }
+
+    /// Runs `/name`. Returns `None` if there's no such command.
+    pub fn dispatch(&self, name: &str, ctx: &mut CommandContext) -> Option<Result<(), String>> {
+        self.handlers.get(name).map(|handler| handler(ctx))
+    }
+}
+
+/// Commands every server starts with. Clients complete these names too.
+pub const DEFAULT_COMMANDS: &[(&str, Handler)] = &[
+    ("who", who),
+    ("nick", nick),
+    ("me", me),
+    ("topic", topic),
+    ("uptime", uptime),
+    ("history", history),
+    ("search", search),
+    ("register", register),
+];
+
+impl Default for Dispatcher {
+    fn default() -> Self {
+        let mut dispatcher = Dispatcher::empty();
+
+        for &(name, handler) in DEFAULT_COMMANDS {
+            dispatcher.register(name, handler);
+        }
+
+        dispatcher
+    }
+}
+
+/// `/who [#room]`: everyone connected, or everyone in a room.
+fn who(ctx: &mut CommandContext) -> Result<(), String> {
+    let names: Vec<&str> = match ctx.args.first() {
+        Some(room) => {
+            let room = ctx
+                .rooms
+                .get(room)
+                .ok_or_else(|| format!("There's no {}", room))?;
+            let mut names: Vec<&str> = room.members.iter().map(|m| m.as_str()).collect();
+            names.sort_unstable();
+            names
+        }
+        None => ctx.users.clone(),
+    };
+
+    let text = format!("{} online: {}", names.len(), names.join(", "));
+    ctx.say(text);
+
+    Ok(())
+}
+
+/// `/nick <username>`: go by another guest name. Registered names are only
+/// handed out with their password.
+fn nick(ctx: &mut CommandContext) -> Result<(), String> {
+    let to = match ctx.args {
+        [to] => to.clone(),
+        _ => return Err("Usage: /nick <username>".into()),
+    };
+
+    validate_username(&to)?;
+    if !is_guest(&to) {
+        return Err(format!(
+            "Only names starting with {} can be picked freely, /register {} first",
+            GUEST_PREFIX, to
+        ));
+    }
+    if ctx.users.contains(&to.as_str()) {
+        return Err(format!("{} is taken", to));
+    }
+
+    ctx.emit(Action::Rename {
+        from: ctx.caller.into(),
+        to,
+    })
+}
+
+/// `/me <action>`: tell the room what you're doing.
+fn me(ctx: &mut CommandContext) -> Result<(), String> {
+    if ctx.args.is_empty() {
+        return Err("Usage: /me <action>".into());
+    }
+
+    ctx.emit(Action::Emote {
+        username: ctx.caller.into(),
+        room: ctx.room.into(),
+        message: ctx.text(),
+    })
+}
+
+/// `/topic [text]`: show the room's topic, or change it.
+fn topic(ctx: &mut CommandContext) -> Result<(), String> {
+    if ctx.args.is_empty() {
+        let text = match ctx.rooms.get(ctx.room).and_then(|r| r.topic.as_ref()) {
+            Some(topic) => format!("Topic for {}: {}", ctx.room, topic),
+            None => format!("{} has no topic", ctx.room),
+        };
+        ctx.say(text);
+
+        return Ok(());
+    }
+
+    ctx.emit(Action::SetTopic {
+        username: ctx.caller.into(),
+        room: ctx.room.into(),
+        topic: ctx.text(),
+    })
+}
+
+/// `/uptime`: how long the server has been running.
+fn uptime(ctx: &mut CommandContext) -> Result<(), String> {
+    let text = format!("Up for {}", format_duration(ctx.started.elapsed()));
+    ctx.say(text);
+
+    Ok(())
+}
+
+/// `/history <n>`: the last lines said in the room.
+fn history(ctx: &mut CommandContext) -> Result<(), String> {
+    let n = match ctx.args {
+        [n] => n
+            .parse::<usize>()
+            .map_err(|_| "Usage: /history <n>".to_owned())?,
+        _ => return Err("Usage: /history <n>".into()),
+    };
+
+    if !ctx.features.contains(&Feature::History) {
+        return Err("Your client can't show history".into());
+    }
+
+    let records = ctx.history.last(ctx.room, n.min(MAX_HISTORY_REQUEST));
+    let room = ctx.room;
+    let reply = fit_records(records, ctx.max_frame_size, |records| Message::History {
+        room: room.into(),
+        records,
+    });
+    ctx.replies.extend(reply);
+
+    Ok(())
+}
+
+/// `/search <text> [from:<user>] [since:<time>]`: past lines from the rooms
+/// the caller is in.
+fn search(ctx: &mut CommandContext) -> Result<(), String> {
+    let query = Query::parse(ctx.args)?;
+
+    if !ctx.features.contains(&Feature::History) {
+        return Err("Your client can't show history".into());
+    }
+
+    let rooms = ctx.rooms;
+    let caller = ctx.caller;
+    let visible = |room: &str| rooms.get(room).is_some_and(|r| r.members.contains(caller));
+
+    let records = ctx.history.search(&query, visible, MAX_SEARCH_RESULTS);
+    let text = ctx.text();
+    let reply = fit_records(records, ctx.max_frame_size, |records| {
+        Message::SearchResults {
+            query: text.clone(),
+            records,
+        }
+    });
+    ctx.replies.extend(reply);
+
+    Ok(())
+}
+
+/// `/register <username> <password>`: claim a name, and switch to it.
+fn register(ctx: &mut CommandContext) -> Result<(), String> {
+    let (username, password) = match ctx.args {
+        [username, password] => (username.clone(), password.clone()),
+        _ => return Err("Usage: /register <username> <password>".into()),
+    };
+
+    ctx.accounts.check_new(&username, &password)?;
+    if username != ctx.caller && ctx.users.contains(&username.as_str()) {
+        return Err(format!("{} is taken", username));
+    }
+
+    ctx.emit(Action::Register {
+        from: ctx.caller.into(),
+        username,
+        password,
+    })
+}
+
+fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (days, hours, mins) = (secs / 86400, secs / 3600 % 24, secs / 60 % 60);
+
+    match (days, hours, mins) {
+        (0, 0, 0) => format!("{}s", secs),
+        (0, 0, _) => format!("{}m {}s", mins, secs % 60),
+        (0, _, _) => format!("{}h {}m", hours, mins),
+        _ => format!("{}d {}h", days, hours),
+    }
+}
@@ -0,0 +1,371 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+
+use crate::common::{append_jsonl, open_jsonl, HistoryRecord, Message};
+
+/// Everything said in rooms, kept in memory and appended to a log file so it
+/// survives restarts. Each line of the log is a JSON `HistoryRecord`.
+pub struct History {
+    log: Option<File>,
+    records: Vec<HistoryRecord>,
+    /// Lowercased words to the records they show up in, in order.
+    index: HashMap<String, Vec<usize>>,
+}
+
+/// What `/search` looks for. Every word has to show up in the text, as a
+/// whole word unless it has punctuation in it, like `8080/tcp`.
+#[derive(Debug, Default, PartialEq)]
+pub struct Query {
+    pub words: Vec<String>,
+    pub from: Option<String>,
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl Query {
+    /// Parses `/search` arguments: words to look for, plus `from:<user>` and
+    /// `since:<time>` filters. Times are either relative, like `30m`, `2h` or
+    /// `1d`, or dates, like `2024-05-01`, `2024-05-01T14:30`, `today` and
+    /// `yesterday`, all in local time.
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut query = Query::default();
+
+        for arg in args {
+            if let Some(user) = arg.strip_prefix("from:") {
+                query.from = Some(user.to_owned());
+            } else if let Some(time) = arg.strip_prefix("since:") {
+                query.since = Some(parse_time(time, Local::now())?);
+            } else {
+                query.words.push(arg.to_lowercase());
+            }
+        }
+
+        if query == Query::default() {
+            return Err("Usage: /search <text> [from:<user>] [since:<time>]".into());
+        }
+
+        Ok(query)
+    }
+
+    fn matches(&self, record: &HistoryRecord) -> bool {
+        let text = record.text.to_lowercase();
+        let text_words = words(&text);
+
+        let has_words = self.words.iter().all(|w| {
+            if is_word(w) {
+                text_words.contains(w.as_str())
+            } else {
+                text.contains(w.as_str())
+            }
+        });
+
+        has_words
+            && self.from.as_ref().is_none_or(|from| record.from == *from)
+            && self.since.is_none_or(|since| record.timestamp >= since)
+    }
+}
+
+fn parse_time(time: &str, now: DateTime<Local>) -> Result<DateTime<Utc>, String> {
+    let invalid = || format!("Can't make sense of the time {}", time);
+
+    let midnight = |date: NaiveDate| {
+        Local
+            .from_local_datetime(&date.and_hms_opt(0, 0, 0).expect("Midnight"))
+            .earliest()
+            .map(|t| t.with_timezone(&Utc))
+            .ok_or_else(invalid)
+    };
+
+    match time {
+        "today" => return midnight(now.date_naive()),
+        "yesterday" => return midnight(now.date_naive() - Duration::days(1)),
+        _ => (),
+    }
+
+    if let Some(unit) = time.chars().last().filter(|c| "smhd".contains(*c)) {
+        if let Ok(n) = time[..time.len() - 1].parse::<i64>() {
+            // Anyone can type this, so huge numbers are refused, not a panic
+            let ago = match unit {
+                _ if n < 0 => None,
+                's' => Duration::try_seconds(n),
+                'm' => Duration::try_minutes(n),
+                'h' => Duration::try_hours(n),
+                _ => Duration::try_days(n),
+            };
+
+            return ago
+                .and_then(|ago| now.checked_sub_signed(ago))
+                .map(|t| t.with_timezone(&Utc))
+                .ok_or_else(invalid);
+        }
+    }
+
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return midnight(date);
+    }
+
+    NaiveDateTime::parse_from_str(time, "%Y-%m-%dT%H:%M")
+        .ok()
+        .and_then(|t| Local.from_local_datetime(&t).earliest())
+        .map(|t| t.with_timezone(&Utc))
+        .or_else(|| {
+            DateTime::parse_from_rfc3339(time)
+                .ok()
+                .map(|t| t.with_timezone(&Utc))
+        })
+        .ok_or_else(invalid)
+}
+
+fn is_word(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(char::is_alphanumeric)
+}
+
+/// Splits text into lowercased words for the index.
+fn words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+impl History {
+    /// History that's gone once the server stops.
+    pub fn in_memory() -> Self {
+        History {
+            log: None,
+            records: vec![],
+            index: HashMap::new(),
+        }
+    }
+
+    /// Picks up the log at `path`, if there's one, and carries on with it.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let mut history = History::in_memory();
+        let log = open_jsonl(path, |record| history.keep(record))?;
+        history.log = Some(log);
+
+        Ok(history)
+    }
+
+    /// Records a line of chat, stamped with the current time.
+    pub fn append(&mut self, from: &str, room: &str, text: &str) -> io::Result<()> {
+        let record = HistoryRecord {
+            timestamp: Utc::now(),
+            from: from.to_owned(),
+            room: room.to_owned(),
+            text: text.to_owned(),
+        };
+
+        if let Some(log) = self.log.as_mut() {
+            append_jsonl(log, &record)?;
+        }
+
+        self.keep(record);
+
+        Ok(())
+    }
+
+    fn keep(&mut self, record: HistoryRecord) {
+        let position = self.records.len();
+
+        for word in words(&record.text) {
+            self.index.entry(word).or_default().push(position);
+        }
+
+        self.records.push(record);
+    }
+
+    /// Up to `n` of the latest lines said in `room`, oldest first.
+    pub fn last(&self, room: &str, n: usize) -> Vec<HistoryRecord> {
+        let mut last: Vec<HistoryRecord> = self
+            .records
+            .iter()
+            .rev()
+            .filter(|r| r.room == room)
+            .take(n)
+            .cloned()
+            .collect();
+        last.reverse();
+
+        last
+    }
+
+    /// Up to `limit` of the latest lines matching `query` said in rooms
+    /// `visible` lets through, oldest first.
+    pub fn search<F>(&self, query: &Query, visible: F, limit: usize) -> Vec<HistoryRecord>
+    where
+        F: Fn(&str) -> bool,
+    {
+        // Only lines holding every whole word can match, so it's enough to
+        // go through the ones holding the rarest
+        let postings = query
+            .words
+            .iter()
+            .filter(|w| is_word(w))
+            .map(|w| self.index.get(w.as_str()).map_or(&[][..], |p| p.as_slice()))
+            .min_by_key(|p| p.len());
+
+        let candidates: Box<dyn DoubleEndedIterator<Item = &HistoryRecord>> = match postings {
+            Some(postings) => Box::new(postings.iter().map(|&i| &self.records[i])),
+            None => Box::new(self.records.iter()),
+        };
+
+        let mut found: Vec<HistoryRecord> = candidates
+            .rev()
+            .filter(|r| visible(&r.room) && query.matches(r))
+            .take(limit)
+            .cloned()
+            .collect();
+        found.reverse();
+
+        found
+    }
+}
+
+/// Leaves out the oldest `records` until `wrap` turns the rest into a message
+/// that fits in a frame of `max_frame_size`. Returns it, followed by a notice
+/// if anything was left out.
+pub fn fit_records<F>(
+    mut records: Vec<HistoryRecord>,
+    max_frame_size: usize,
+    wrap: F,
+) -> Vec<Message>
+where
+    F: Fn(Vec<HistoryRecord>) -> Message,
+{
+    let size = |json: Result<String, serde_json::Error>| json.map_or(usize::MAX, |s| s.len());
+
+    // Records are joined with commas, so each one adds its own length and
+    // a comma, bar the first
+    let mut total = size(serde_json::to_string(&wrap(vec![]))).saturating_sub(1);
+    let mut kept = 0;
+    for record in records.iter().rev() {
+        total = total.saturating_add(size(serde_json::to_string(record)).saturating_add(1));
+        if total > max_frame_size {
+            break;
+        }
+        kept += 1;
+    }
+
+    let left_out = records.len() - kept;
+    let mut reply = vec![wrap(records.split_off(left_out))];
+    if left_out > 0 {
+        let text = format!("{} older lines were too much to send", left_out);
+        reply.push(Message::System { text });
+    }
+
+    reply
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 10, 15, 0, 0).unwrap()
+    }
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    #[test]
+    fn relative_times_count_back_from_now() {
+        let since = parse_time("30m", now()).unwrap();
+        assert_eq!(since, (now() - Duration::minutes(30)).with_timezone(&Utc));
+
+        let since = parse_time("2d", now()).unwrap();
+        assert_eq!(since, (now() - Duration::days(2)).with_timezone(&Utc));
+    }
+
+    #[test]
+    fn days_start_at_local_midnight() {
+        let midnight = |d| {
+            Local
+                .with_ymd_and_hms(2024, 5, d, 0, 0, 0)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+
+        assert_eq!(parse_time("today", now()).unwrap(), midnight(10));
+        assert_eq!(parse_time("yesterday", now()).unwrap(), midnight(9));
+        assert_eq!(parse_time("2024-05-01", now()).unwrap(), midnight(1));
+    }
+
+    #[test]
+    fn dates_can_have_a_time() {
+        let expected = Local
+            .with_ymd_and_hms(2024, 5, 1, 14, 30, 0)
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(parse_time("2024-05-01T14:30", now()).unwrap(), expected);
+    }
+
+    #[test]
+    fn out_of_range_times_are_refused() {
+        for time in [
+            "9999999999999999d",
+            "9223372036854775807s",
+            "-5m",
+            "5y",
+            "soon",
+        ] {
+            let result = parse_time(time, now());
+            assert_eq!(
+                result,
+                Err(format!("Can't make sense of the time {}", time)),
+                "{}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn queries_split_words_from_filters() {
+        let query = Query::parse(&args("Deploy from:alice since:1h")).unwrap();
+
+        assert_eq!(query.words, vec!["deploy".to_owned()]);
+        assert_eq!(query.from.as_deref(), Some("alice"));
+        assert!(query.since.is_some());
+    }
+
+    #[test]
+    fn queries_need_something_to_look_for() {
+        assert!(Query::parse(&[]).is_err());
+        assert!(Query::parse(&args("since:9999999999999999d")).is_err());
+    }
+
+    #[test]
+    fn records_are_left_out_oldest_first_to_fit() {
+        let records: Vec<HistoryRecord> = (0..3)
+            .map(|i| HistoryRecord {
+                timestamp: now().with_timezone(&Utc),
+                from: "alice".into(),
+                room: "lobby".into(),
+                text: format!("line {}", i),
+            })
+            .collect();
+        let wrap = |records| Message::History {
+            room: "lobby".into(),
+            records,
+        };
+        let all = wrap(records.clone()).encode().unwrap().len();
+
+        let notice = |n| Message::System {
+            text: format!("{} older lines were too much to send", n),
+        };
+
+        let reply = fit_records(records.clone(), all, wrap);
+        assert_eq!(reply, vec![wrap(records.clone())]);
+
+        let reply = fit_records(records.clone(), all - 1, wrap);
+        assert!(reply[0].encode().unwrap().len() < all);
+        assert_eq!(reply, vec![wrap(records[1..].to_vec()), notice(1)]);
+
+        let reply = fit_records(records, 10, wrap);
+        assert_eq!(reply, vec![wrap(vec![]), notice(3)]);
+    }
+}
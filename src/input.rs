@@ -0,0 +1,188 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+#[cfg(unix)]
+use std::sync::Mutex;
+use std::sync::{Arc, RwLock};
+
+#[cfg(unix)]
+use nix::sys::termios::{self, SetArg, Termios};
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::FileHistory;
+use rustyline::validate::Validator;
+use rustyline::{Config, Editor, Helper};
+
+use crate::commands::Invocation;
+use crate::transport::Endpoint;
+
+/// Lines kept in the history file, older ones are dropped.
+const MAX_HISTORY: usize = 1000;
+
+/// How the terminal was before line editing got to it, for `restore_terminal`.
+#[cfg(unix)]
+static SAVED_TERMINAL: Mutex<Option<Termios>> = Mutex::new(None);
+
+/// Reads what's typed in the line by line client. Lines can be edited and
+/// gone back to, also from earlier runs against the same server, Ctrl-R
+/// searches through them, and Tab completes command names and the names of
+/// whoever's online.
+pub struct Input {
+    editor: Editor<Completion, FileHistory>,
+    /// Where the history is kept, if anywhere.
+    history: Option<PathBuf>,
+}
+
+impl Input {
+    /// Sets up editing for `endpoint`, keeping the history in a file of its
+    /// own under `dir`. `commands` are completed after a `/`, anything else
+    /// from `users`.
+    pub fn new(
+        endpoint: &Endpoint,
+        dir: Option<&Path>,
+        commands: Vec<&'static str>,
+        users: Arc<RwLock<Vec<String>>>,
+    ) -> rustyline::Result<Self> {
+        let config = Config::builder()
+            .max_history_size(MAX_HISTORY)?
+            .history_ignore_dups(true)?
+            .history_ignore_space(true)
+            .build();
+        let mut editor = Editor::with_config(config)?;
+        editor.set_helper(Some(Completion { commands, users }));
+
+        #[cfg(unix)]
+        if let Ok(saved) = termios::tcgetattr(std::io::stdin()) {
+            *SAVED_TERMINAL.lock().unwrap() = Some(saved);
+        }
+
+        let history = dir.map(|dir| dir.join(history_name(endpoint)));
+        if let Some(path) = &history {
+            // Nothing typed against this server yet
+            if path.exists() {
+                editor.load_history(path)?;
+            }
+        }
+
+        Ok(Input { editor, history })
+    }
+
+    /// Reads a line after showing `prompt`. Returns `None` once there's
+    /// nothing more to read, or on Ctrl-C.
+    pub fn readline(&mut self, prompt: &str) -> Option<String> {
+        match self.editor.readline(prompt) {
+            Ok(line) => {
+                self.remember(&line);
+                Some(line.trim().into())
+            }
+            Err(ReadlineError::Eof | ReadlineError::Interrupted) => None,
+            Err(e) => {
+                eprintln!("ERROR: Could not read input: {}", e);
+                None
+            }
+        }
+    }
+
+    fn remember(&mut self, line: &str) {
+        // Passwords don't belong in a file
+        let registering = Invocation::parse(line).is_some_and(|i| i.name == "register");
+        if line.trim().is_empty() || registering {
+            return;
+        }
+        if !self.editor.add_history_entry(line).unwrap_or(false) {
+            return;
+        }
+
+        if let Some(path) = self.history.clone() {
+            let saved = path
+                .parent()
+                .map_or(Ok(()), fs::create_dir_all)
+                .map_err(ReadlineError::from)
+                .and_then(|_| self.editor.append_history(&path));
+            if let Err(e) = saved {
+                eprintln!("ERROR: Could not save history to {}: {}", path.display(), e);
+                // Once is enough
+                self.history = None;
+            }
+        }
+    }
+}
+
+/// Puts the terminal back the way it was before any `Input` got to it.
+/// Rustyline only does that once `readline` returns, so quitting while it
+/// waits would leave the terminal in raw mode otherwise.
+pub fn restore_terminal() {
+    #[cfg(unix)]
+    if let Some(saved) = SAVED_TERMINAL.lock().unwrap().as_ref() {
+        let _ = termios::tcsetattr(std::io::stdin(), SetArg::TCSADRAIN, saved);
+    }
+}
+
+/// File name for the history of `endpoint`, with anything that wouldn't do
+/// in one replaced.
+fn history_name(endpoint: &Endpoint) -> String {
+    endpoint
+        .to_string()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Completes the word under the cursor.
+struct Completion {
+    commands: Vec<&'static str>,
+    users: Arc<RwLock<Vec<String>>>,
+}
+
+impl Completer for Completion {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| c.is_ascii_whitespace())
+            .map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+
+        let candidates = match word.strip_prefix('/') {
+            Some(name) if start == 0 => self
+                .commands
+                .iter()
+                .filter(|c| c.starts_with(name))
+                .map(|c| format!("/{} ", c))
+                .collect(),
+            _ => self
+                .users
+                .read()
+                .unwrap()
+                .iter()
+                .filter(|u| u.starts_with(word))
+                .map(|u| format!("{} ", u))
+                .collect(),
+        };
+
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for Completion {
+    type Hint = String;
+}
+
+impl Highlighter for Completion {}
+
+impl Validator for Completion {}
+
+impl Helper for Completion {}
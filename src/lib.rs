@@ -0,0 +1,29 @@
+//! A small chat server and client, speaking length-prefixed JSON frames over
+//! TCP or Unix sockets, optionally wrapped in TLS.
+//!
+//! `ServerBuilder` puts a server together and `Client` talks to one from
+//! code. The `chat-rs` binary is a command line over both.
+
+pub mod accounts;
+pub mod client;
+pub mod common;
+pub mod dispatch;
+pub mod history;
+pub mod server;
+pub mod tls;
+pub mod transport;
+
+// Only the binary needs it, and it leans on the client's commands
+#[doc(hidden)]
+pub mod pipe;
+
+mod commands;
+mod input;
+mod output;
+mod tui;
+
+pub use client::{Client, ClientConfig, Credentials};
+pub use common::{Message, ServerError};
+pub use output::Output;
+pub use server::{Handle, Server, ServerBuilder, ServerConfig};
+pub use transport::Endpoint;
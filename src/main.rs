@@ -1,14 +1,15 @@
 extern crate clap;
 
+use std::env;
 use std::net::{IpAddr, SocketAddr};
-use std::u16;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::time::Duration;
 
 use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
 
-// internals
-mod client;
-mod common;
-mod server;
+use chat_rs::{client, common, pipe, server, tls, transport};
 
 fn main() {
     let server_arg = Arg::with_name("server")
@@ -31,12 +32,167 @@ fn main() {
             )),
         });
 
+    let default_max_frame_size = common::DEFAULT_MAX_FRAME_SIZE.to_string();
+    let max_frame_size_arg = Arg::with_name("max-frame-size")
+        .long("max-frame-size")
+        .help("Biggest message accepted, in bytes")
+        .takes_value(true)
+        .default_value(&default_max_frame_size)
+        .validator(|v| match v.parse::<u32>() {
+            Ok(n) if n > 0 => Ok(()),
+            _ => Err(format!(
+                "Max frame size should be a positive value up to {}.",
+                u32::MAX
+            )),
+        });
+
+    let default_max_queue = common::DEFAULT_MAX_QUEUE.to_string();
+    let queue_size_arg = Arg::with_name("queue-size")
+        .long("queue-size")
+        .help("How many messages may wait for a slow user")
+        .takes_value(true)
+        .default_value(&default_max_queue)
+        .validator(|v| match v.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(()),
+            _ => Err("Queue size should be a positive value.".into()),
+        });
+
+    let overflow_arg = Arg::with_name("overflow")
+        .long("overflow")
+        .help("What to do when a user's queue is full")
+        .takes_value(true)
+        .possible_values(&["drop-oldest", "disconnect"])
+        .default_value("drop-oldest");
+
+    let grace_arg = Arg::with_name("grace")
+        .long("grace")
+        .help("Seconds users get to wrap up when the server is stopped")
+        .takes_value(true)
+        .default_value("0")
+        .validator(|v| match v.parse::<u64>() {
+            Ok(_) => Ok(()),
+            _ => Err("Grace period should be a number of seconds.".into()),
+        });
+
+    let default_heartbeat = common::DEFAULT_HEARTBEAT.as_secs().to_string();
+    let heartbeat_arg = Arg::with_name("heartbeat")
+        .long("heartbeat")
+        .help("Seconds of silence before pinging the other side")
+        .takes_value(true)
+        .default_value(&default_heartbeat)
+        .validator(validate_seconds);
+
+    let default_idle_timeout = common::DEFAULT_IDLE_TIMEOUT.as_secs().to_string();
+    let idle_timeout_arg = Arg::with_name("idle-timeout")
+        .long("idle-timeout")
+        .help("Seconds of silence before dropping a user")
+        .takes_value(true)
+        .default_value(&default_idle_timeout)
+        .validator(validate_seconds);
+
+    let history_file_arg = Arg::with_name("history-file")
+        .long("history-file")
+        .help("Where to keep what's said in rooms")
+        .takes_value(true)
+        .default_value("history.jsonl");
+
+    let default_history_depth = common::DEFAULT_HISTORY_DEPTH.to_string();
+    let history_depth_arg = Arg::with_name("history-depth")
+        .long("history-depth")
+        .help("How many past lines users see when they join")
+        .takes_value(true)
+        .default_value(&default_history_depth)
+        .validator(|v| match v.parse::<usize>() {
+            Ok(_) => Ok(()),
+            _ => Err("History depth should be a number of lines.".into()),
+        });
+
+    let accounts_file_arg = Arg::with_name("accounts-file")
+        .long("accounts-file")
+        .help("Where registered usernames and their password hashes are kept")
+        .takes_value(true)
+        .default_value("accounts.jsonl");
+
+    let cert_arg = Arg::with_name("cert")
+        .long("cert")
+        .help("PEM certificate chain to serve TLS with")
+        .takes_value(true)
+        .requires("key");
+
+    let key_arg = Arg::with_name("key")
+        .long("key")
+        .help("PEM private key for --cert")
+        .takes_value(true)
+        .requires("cert");
+
+    let ca_arg = Arg::with_name("ca")
+        .long("ca")
+        .help("Connect over TLS, trusting the server if its certificate is signed by one in this PEM file")
+        .takes_value(true);
+
+    let insecure_arg = Arg::with_name("insecure")
+        .long("insecure")
+        .help("Connect over TLS without checking the server's certificate")
+        .conflicts_with("ca");
+
+    let join_unix_arg = Arg::with_name("unix")
+        .long("unix")
+        .help("Connect to a Unix socket at this path instead")
+        .takes_value(true)
+        .conflicts_with_all(&["server", "port", "ca", "insecure"]);
+
+    let serve_unix_arg = Arg::with_name("unix")
+        .long("unix")
+        .help("Listen on a Unix socket at this path, never over TLS. Only there unless -s or -p are given too")
+        .takes_value(true);
+
     let username_arg = Arg::with_name("username")
         .long("username")
         .short("u")
-        .help("Sets your username")
+        .help("Sets your username. Registered ones need a password, read from CHAT_PASSWORD or asked for")
         .takes_value(true);
 
+    let pipe_arg = Arg::with_name("pipe")
+        .long("pipe")
+        .help("Send each line from stdin, then quit. What comes in is printed as tab separated fields, unless --output json")
+        .requires("username");
+
+    let wait_arg = Arg::with_name("wait")
+        .long("wait")
+        .help("Replies to wait for before quitting, like lines said or answers to commands")
+        .takes_value(true)
+        .requires("pipe")
+        .validator(|v| match v.parse::<usize>() {
+            Ok(_) => Ok(()),
+            _ => Err("Replies should be a number.".into()),
+        });
+
+    let timeout_arg = Arg::with_name("timeout")
+        .long("timeout")
+        .help("Seconds to wait for replies")
+        .takes_value(true)
+        .default_value("10")
+        .validator(validate_seconds);
+
+    let room_arg = Arg::with_name("room")
+        .long("room")
+        .help("Room lines from stdin go to")
+        .takes_value(true)
+        .requires("pipe")
+        .validator(|v| common::validate_room(&v));
+
+    let output_arg = Arg::with_name("output")
+        .long("output")
+        .help("How to print what comes in. With json it's one event per line, and stdout has nothing else")
+        .takes_value(true)
+        .possible_values(&["text", "json"])
+        .default_value("text");
+
+    let tui_arg = Arg::with_name("tui")
+        .long("tui")
+        .help("Full screen, with who's online on the side. Up and Down go through what you typed, PageUp and PageDown through what was said")
+        .conflicts_with_all(&["pipe", "output"]);
+
     let app = App::new("chat-rs")
         .author("Johnny Santos <johnnyadsantos@gmail.com>")
         .about("A chat using tcp. Made for learning purposes")
@@ -47,13 +203,36 @@ fn main() {
                 .about("Join a chat server")
                 .arg(&server_arg)
                 .arg(&port_arg)
-                .arg(&username_arg),
+                .arg(&username_arg)
+                .arg(&max_frame_size_arg)
+                .arg(&heartbeat_arg)
+                .arg(&ca_arg)
+                .arg(&insecure_arg)
+                .arg(&join_unix_arg)
+                .arg(&pipe_arg)
+                .arg(&wait_arg)
+                .arg(&timeout_arg)
+                .arg(&room_arg)
+                .arg(&output_arg)
+                .arg(&tui_arg),
         )
         .subcommand(
             SubCommand::with_name("server")
                 .about("Start a chat server")
                 .arg(&server_arg)
-                .arg(&port_arg),
+                .arg(&port_arg)
+                .arg(&max_frame_size_arg)
+                .arg(&queue_size_arg)
+                .arg(&overflow_arg)
+                .arg(&grace_arg)
+                .arg(&heartbeat_arg)
+                .arg(&idle_timeout_arg)
+                .arg(&history_file_arg)
+                .arg(&history_depth_arg)
+                .arg(&accounts_file_arg)
+                .arg(&cert_arg)
+                .arg(&key_arg)
+                .arg(&serve_unix_arg),
         )
         .setting(AppSettings::ColorAuto)
         .setting(AppSettings::SubcommandRequiredElseHelp);
@@ -61,19 +240,114 @@ fn main() {
     let matches = app.get_matches();
 
     if let Some(matches) = matches.subcommand_matches("join") {
-        let addr = get_server_addr(&matches);
+        let endpoint = match matches.value_of("unix") {
+            Some(path) => unix_endpoint(path),
+            None => transport::Endpoint::Tcp(get_server_addr(matches)),
+        };
         let username = matches.value_of("username");
+        // Registered names need a password, taken from here instead of asked
+        // for when set
+        let password = env::var("CHAT_PASSWORD").ok();
 
-        client::join(addr, username);
+        let config = client::ClientConfig {
+            max_frame_size: get_max_frame_size(matches),
+            heartbeat: get_seconds(matches, "heartbeat"),
+            tls: get_client_tls(matches),
+            output: matches
+                .value_of("output")
+                .expect("Output format")
+                .parse()
+                .expect("Invalid output format"),
+            tui: matches.is_present("tui"),
+            // Like ~/.chat-rs/history/127.0.0.1_4000
+            history_dir: env::var_os("HOME")
+                .map(|home| PathBuf::from(home).join(".chat-rs").join("history")),
+        };
+
+        if matches.is_present("pipe") {
+            let credentials = client::Credentials {
+                username: username.expect("Username").into(),
+                password,
+            };
+            let options = pipe::PipeOptions {
+                room: matches.value_of("room").map(String::from),
+                wait: matches
+                    .value_of("wait")
+                    .map_or(0, |n| n.parse().expect("Replies isn't a valid number")),
+                timeout: get_seconds(matches, "timeout"),
+            };
+
+            pipe::pipe(&endpoint, credentials, &config, options);
+        } else {
+            client::join(endpoint, username, password, config);
+        }
     }
 
     if let Some(matches) = matches.subcommand_matches("server") {
-        let addr = get_server_addr(&matches);
+        let mut endpoints = vec![];
+        let unix = matches.value_of("unix");
+
+        if unix.is_none() || matches.occurrences_of("server") + matches.occurrences_of("port") > 0 {
+            endpoints.push(transport::Endpoint::Tcp(get_server_addr(matches)));
+        }
+        if let Some(path) = unix {
+            endpoints.push(unix_endpoint(path));
+        }
+
+        let config = server::ServerConfig {
+            max_frame_size: get_max_frame_size(matches),
+            max_queue: matches
+                .value_of("queue-size")
+                .expect("Queue size")
+                .parse()
+                .expect("Queue size isn't a valid number"),
+            overflow_policy: matches
+                .value_of("overflow")
+                .expect("Overflow policy")
+                .parse()
+                .expect("Invalid overflow policy"),
+            grace: get_seconds(matches, "grace"),
+            heartbeat: get_seconds(matches, "heartbeat"),
+            idle_timeout: get_seconds(matches, "idle-timeout"),
+            history_file: matches.value_of("history-file").map(PathBuf::from),
+            history_depth: matches
+                .value_of("history-depth")
+                .expect("History depth")
+                .parse()
+                .expect("History depth isn't a valid number"),
+            accounts_file: matches.value_of("accounts-file").map(PathBuf::from),
+            tls: get_server_tls(matches),
+        };
+
+        let mut builder = server::ServerBuilder::new().config(config);
+        for endpoint in endpoints {
+            builder = builder.bind(endpoint);
+        }
+        let server = builder.build().expect("Failed to start server");
 
-        server::start(addr).expect("Failed to serve");
+        let handle = server.handle();
+        ctrlc::set_handler(move || {
+            handle
+                .shutdown("Server is shutting down")
+                .unwrap_or_else(|e| eprintln!("ERROR: Could not shutdown: {:?}", e));
+        })
+        .expect("Failed to set ctrl-c handler");
+
+        server.run().expect("Failed to serve");
     }
 }
 
+#[cfg(unix)]
+fn unix_endpoint(path: &str) -> transport::Endpoint {
+    transport::Endpoint::Unix(PathBuf::from(path))
+}
+
+#[cfg(not(unix))]
+fn unix_endpoint(_: &str) -> transport::Endpoint {
+    eprintln!("ERROR: Unix sockets aren't available on this platform");
+    process::exit(1);
+}
+
 fn get_server_addr(matches: &ArgMatches) -> SocketAddr {
     let server = matches.value_of("server").expect("Server address");
     let port = matches
@@ -86,3 +360,62 @@ fn get_server_addr(matches: &ArgMatches) -> SocketAddr {
 
     SocketAddr::new(ip_addr, port)
 }
+
+fn get_max_frame_size(matches: &ArgMatches) -> usize {
+    matches
+        .value_of("max-frame-size")
+        .expect("Max frame size")
+        .parse::<usize>()
+        .expect("Max frame size isn't a valid number")
+}
+
+fn get_server_tls(matches: &ArgMatches) -> Option<Arc<rustls::ServerConfig>> {
+    let cert = Path::new(matches.value_of("cert")?);
+    let key = Path::new(
+        matches
+            .value_of("key")
+            .expect("Key to go with the certificate"),
+    );
+
+    match tls::server_config(cert, key) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            eprintln!("ERROR: Could not set up TLS: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn get_client_tls(matches: &ArgMatches) -> Option<Arc<rustls::ClientConfig>> {
+    let ca = matches.value_of("ca").map(Path::new);
+    let insecure = matches.is_present("insecure");
+
+    if ca.is_none() && !insecure {
+        return None;
+    }
+
+    match tls::client_config(ca, insecure) {
+        Ok(config) => Some(config),
+        Err(e) => {
+            eprintln!("ERROR: Could not set up TLS: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn get_seconds(matches: &ArgMatches, name: &str) -> Duration {
+    let secs = matches
+        .value_of(name)
+        .expect(name)
+        .parse()
+        .expect("Not a valid number of seconds");
+
+    Duration::from_secs(secs)
+}
+
+fn validate_seconds(v: String) -> Result<(), String> {
+    match v.parse::<u64>() {
+        Ok(n) if n > 0 => Ok(()),
+        _ => Err("Should be a positive number of seconds.".into()),
+    }
+}
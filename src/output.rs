@@ -0,0 +1,184 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+use crate::common::{Message, DEFAULT_ROOM};
+
+/// How the client prints what comes in.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    /// Meant to be read by people.
+    Text,
+    /// One `Event` per line, as JSON. Nothing else goes to stdout.
+    Json,
+}
+
+impl FromStr for Output {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Output::Text),
+            "json" => Ok(Output::Json),
+            _ => Err(format!("Unknown output format: {}", s)),
+        }
+    }
+}
+
+/// What an `Event` is about.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Chat,
+    Direct,
+    Emote,
+    /// Something the server itself says, like answers to commands.
+    Notice,
+    Error,
+    /// Someone connected.
+    Join,
+    /// Someone disconnected, `text` being why if they said.
+    Leave,
+    Rename,
+    JoinRoom,
+    PartRoom,
+    /// A past line, replayed when joining or asked for.
+    History,
+    SearchResult,
+    /// A room as listed by `/rooms`.
+    Room,
+    Shutdown,
+}
+
+/// Something that happened, as printed with `--output json`. Fields that
+/// don't apply are null, except the ones only a few kinds have, which are
+/// left out.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Event {
+    #[serde(rename = "type")]
+    pub kind: EventKind,
+    pub sender: Option<String>,
+    pub room: Option<String>,
+    /// When it was said, or for live events, when it came in.
+    pub timestamp: DateTime<Utc>,
+    pub text: Option<String>,
+    /// Who a direct message is for, or someone's new name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+    /// Users in a listed room.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub members: Option<usize>,
+}
+
+impl Event {
+    fn new(kind: EventKind) -> Self {
+        Event {
+            kind,
+            sender: None,
+            room: None,
+            timestamp: Utc::now(),
+            text: None,
+            to: None,
+            members: None,
+        }
+    }
+
+    fn sender(mut self, sender: &str) -> Self {
+        self.sender = Some(sender.to_owned());
+        self
+    }
+
+    fn room(mut self, room: &str) -> Self {
+        self.room = Some(room.to_owned());
+        self
+    }
+
+    fn text(mut self, text: &str) -> Self {
+        self.text = Some(text.to_owned());
+        self
+    }
+}
+
+/// What `msg` amounts to. Most messages are a single event, lists of past
+/// lines or rooms are one per item, and the ones that are only part of the
+/// protocol are none.
+pub fn events(msg: &Message) -> Vec<Event> {
+    use EventKind::*;
+
+    match msg {
+        Message::Chat { from, room, text } => {
+            let room = room.as_deref().unwrap_or(DEFAULT_ROOM);
+            vec![Event::new(Chat).sender(from).room(room).text(text)]
+        }
+        Message::Direct { from, to, text } => vec![Event {
+            to: Some(to.clone()),
+            ..Event::new(Direct).sender(from).text(text)
+        }],
+        Message::Emote { from, room, text } => {
+            vec![Event::new(Emote).sender(from).room(room).text(text)]
+        }
+        Message::System { text } => vec![Event::new(Notice).text(text)],
+        Message::Error { reason, .. } => vec![Event::new(Error).text(reason)],
+        Message::Join { username, .. } => vec![Event::new(Join).sender(username)],
+        Message::Goodbye { username, reason } => {
+            let event = Event::new(Leave).sender(username);
+            vec![match reason {
+                Some(reason) => event.text(reason),
+                None => event,
+            }]
+        }
+        Message::Renamed { from, to } => vec![Event {
+            to: Some(to.clone()),
+            ..Event::new(Rename).sender(from)
+        }],
+        Message::JoinRoom { username, room } => {
+            vec![Event::new(JoinRoom).sender(username).room(room)]
+        }
+        Message::PartRoom { username, room } => {
+            vec![Event::new(PartRoom).sender(username).room(room)]
+        }
+        Message::History { records, .. } | Message::SearchResults { records, .. } => {
+            let kind = match msg {
+                Message::History { .. } => History,
+                _ => SearchResult,
+            };
+
+            records
+                .iter()
+                .map(|r| Event {
+                    timestamp: r.timestamp,
+                    ..Event::new(kind).sender(&r.from).room(&r.room).text(&r.text)
+                })
+                .collect()
+        }
+        Message::Rooms { rooms } => rooms
+            .iter()
+            .map(|r| Event {
+                members: Some(r.members),
+                ..Event::new(Room).room(&r.name)
+            })
+            .collect(),
+        Message::Shutdown { reason, .. } => vec![Event::new(Shutdown).text(reason)],
+        _ => vec![],
+    }
+}
+
+/// Prints what `msg` amounts to as JSON, one event per line.
+pub fn print_json(msg: &Message) {
+    for event in events(msg) {
+        match serde_json::to_string(&event) {
+            Ok(line) => println!("{}", line),
+            Err(e) => eprintln!("ERROR: Could not print event: {}", e),
+        }
+    }
+}
+
+/// Prints a line of our own, as opposed to something that came in. With JSON
+/// output stdout is kept for events, so it goes to stderr instead.
+pub fn say(output: Output, line: &str) {
+    match output {
+        Output::Text => println!("{}", line),
+        Output::Json => eprintln!("{}", line),
+    }
+}
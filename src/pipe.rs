@@ -0,0 +1,308 @@
+use std::io::{stdin, BufRead};
+use std::process;
+use std::sync::mpsc::{channel, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::client::{
+    follow_room, Client, ClientConfig, Credentials, EXIT_CONNECTION_LOST, EXIT_SERVER_SHUTDOWN,
+};
+use crate::commands::{direct_message, forward_message, join_message, Line, Registry};
+use crate::common::{Message, DEFAULT_ROOM};
+use crate::output::{print_json, Output};
+use crate::transport::Endpoint;
+
+/// Exit code when fewer replies than asked for came in time.
+pub const EXIT_NO_REPLY: i32 = 4;
+
+/// How long to wait for the server between looking at stdin.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// What `join --pipe` does besides sending stdin.
+#[derive(Debug, Clone)]
+pub struct PipeOptions {
+    /// Room lines go to, instead of `DEFAULT_ROOM`.
+    pub room: Option<String>,
+    /// Replies to wait for once stdin is done.
+    pub wait: usize,
+    /// How long they're waited for.
+    pub timeout: Duration,
+}
+
+/// Sends every line read from stdin, then waits for replies if asked to.
+/// Nothing is prompted for, and whatever comes in is printed with
+/// `print_fields`, or as JSON, so scripts can make sense of it.
+///
+/// Lines starting with `/` are commands: `/join <room>` and `/msg <user>
+/// <text>` are handled like the interactive client does, anything else goes
+/// to the server. After a `/join`, stdin waits until the server answers, so
+/// the lines that follow go to the new room.
+pub fn pipe(
+    endpoint: &Endpoint,
+    credentials: Credentials,
+    config: &ClientConfig,
+    options: PipeOptions,
+) {
+    let mut client = Client::connect(endpoint, credentials, config).unwrap_or_else(|e| {
+        eprintln!("ERROR: Could not join {}: {}", endpoint, e);
+        process::exit(1);
+    });
+
+    let commands = Registry::new();
+    let mut room = DEFAULT_ROOM.to_owned();
+    // Set while a join is waiting on the server
+    let mut joining = false;
+    if let Some(joined) = &options.room {
+        send(
+            &mut client,
+            &Message::JoinRoom {
+                username: String::new(),
+                room: joined.clone(),
+            },
+        );
+        joining = true;
+    }
+
+    let lines = read_lines();
+    let mut replies = 0;
+    let mut shutting_down = false;
+    // Set once stdin is done, to when we stop waiting for replies
+    let mut deadline = None;
+
+    loop {
+        while !joining {
+            match lines.try_recv() {
+                Ok(line) => {
+                    if let Some(msg) = parse_line(&commands, &line, &room, client.username()) {
+                        joining = matches!(msg, Message::JoinRoom { .. });
+                        send(&mut client, &msg);
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => {
+                    deadline.get_or_insert_with(|| Instant::now() + options.timeout);
+                    break;
+                }
+            }
+        }
+
+        if deadline.is_some() && replies >= options.wait {
+            break;
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            eprintln!(
+                "ERROR: Got {} of {} replies in {}s",
+                replies,
+                options.wait,
+                options.timeout.as_secs()
+            );
+            let _ = client.close(None);
+            process::exit(EXIT_NO_REPLY);
+        }
+
+        match client.recv(Some(POLL_INTERVAL)) {
+            Ok(Some(msg)) => {
+                follow_room(&mut room, client.username(), &msg);
+                match &msg {
+                    Message::JoinRoom { username, .. } if username == client.username() => {
+                        joining = false
+                    }
+                    // Like a room name that isn't one
+                    Message::Error { .. } => joining = false,
+                    _ => (),
+                }
+                if is_reply(&msg) {
+                    replies += 1;
+                }
+                if let Message::Shutdown { .. } = msg {
+                    shutting_down = true;
+                }
+                match config.output {
+                    Output::Text => print_fields(&msg),
+                    Output::Json => print_json(&msg),
+                }
+            }
+            Ok(None) => (),
+            Err(_) if shutting_down => process::exit(EXIT_SERVER_SHUTDOWN),
+            Err(e) => {
+                eprintln!("ERROR: Lost connection to server: {}", e);
+                process::exit(EXIT_CONNECTION_LOST);
+            }
+        }
+    }
+
+    // Might be gone already
+    let _ = client.close(None);
+}
+
+/// Lines from stdin, as they come. The sender hangs up at EOF.
+fn read_lines() -> Receiver<String> {
+    let (sender, receiver) = channel();
+
+    thread::Builder::new()
+        .name("stdin".into())
+        .spawn(move || {
+            for line in stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        eprintln!("ERROR: Could not read stdin: {}", e);
+                        return;
+                    }
+                };
+
+                if sender.send(line).is_err() {
+                    return;
+                }
+            }
+        })
+        .expect("Could not setup stdin reader");
+
+    receiver
+}
+
+fn send(client: &mut Client, msg: &Message) {
+    if let Err(e) = client.send(msg) {
+        eprintln!("ERROR: Failed to send message: {}", e);
+        process::exit(EXIT_CONNECTION_LOST);
+    }
+}
+
+/// What to send for a line of input, if anything.
+fn parse_line(commands: &Registry, line: &str, room: &str, username: &str) -> Option<Message> {
+    let invocation = match Line::parse(line) {
+        Line::Command(invocation) => invocation,
+        Line::Chat(text) => {
+            return (!text.is_empty()).then(|| Message::Chat {
+                from: username.to_owned(),
+                room: Some(room.to_owned()),
+                text: text.to_owned(),
+            })
+        }
+    };
+
+    match commands.find(invocation.name) {
+        Some(command) if command.name == "join" && command.accepts(&invocation) => {
+            Some(join_message(username, &invocation))
+        }
+        Some(command) if command.name == "msg" && command.accepts(&invocation) => {
+            Some(direct_message(username, &invocation))
+        }
+        _ => Some(forward_message(&invocation, room)),
+    }
+}
+
+/// Whether `msg` is something said, to us or where we are, as opposed to
+/// people coming and going.
+fn is_reply(msg: &Message) -> bool {
+    matches!(
+        msg,
+        Message::Chat { .. }
+            | Message::Direct { .. }
+            | Message::Emote { .. }
+            | Message::System { .. }
+            | Message::Error { .. }
+            | Message::SearchResults { .. }
+            | Message::Rooms { .. }
+    )
+}
+
+/// Prints `msg` as one line per event: its type followed by its fields, all
+/// separated by tabs. Tabs, newlines and backslashes inside fields are
+/// escaped like in C.
+///
+/// ```text
+/// chat           <room> <from> <text>
+/// direct         <from> <to> <text>
+/// emote          <room> <from> <text>
+/// system         <text>
+/// error          <text>
+/// join           <user>
+/// goodbye        <user> <reason>
+/// renamed        <from> <to>
+/// join_room      <user> <room>
+/// part_room      <user> <room>
+/// history        <time> <room> <from> <text>
+/// search_result  <time> <room> <from> <text>
+/// room           <room> <members>
+/// shutdown       <reason>
+/// ```
+///
+/// Times are RFC 3339, in UTC.
+pub fn print_fields(msg: &Message) {
+    let fields: Vec<Vec<String>> = match msg {
+        Message::Chat { from, room, text } => {
+            let room = room.as_deref().unwrap_or(DEFAULT_ROOM);
+            vec![vec!["chat".into(), room.into(), from.clone(), text.clone()]]
+        }
+        Message::Direct { from, to, text } => {
+            vec![vec![
+                "direct".into(),
+                from.clone(),
+                to.clone(),
+                text.clone(),
+            ]]
+        }
+        Message::Emote { from, room, text } => {
+            vec![vec![
+                "emote".into(),
+                room.clone(),
+                from.clone(),
+                text.clone(),
+            ]]
+        }
+        Message::System { text } => vec![vec!["system".into(), text.clone()]],
+        Message::Error { reason, .. } => vec![vec!["error".into(), reason.clone()]],
+        Message::Join { username, .. } => vec![vec!["join".into(), username.clone()]],
+        Message::Goodbye { username, reason } => vec![vec![
+            "goodbye".into(),
+            username.clone(),
+            reason.clone().unwrap_or_default(),
+        ]],
+        Message::Renamed { from, to } => vec![vec!["renamed".into(), from.clone(), to.clone()]],
+        Message::JoinRoom { username, room } => {
+            vec![vec!["join_room".into(), username.clone(), room.clone()]]
+        }
+        Message::PartRoom { username, room } => {
+            vec![vec!["part_room".into(), username.clone(), room.clone()]]
+        }
+        Message::History { records, .. } | Message::SearchResults { records, .. } => {
+            let kind = match msg {
+                Message::History { .. } => "history",
+                _ => "search_result",
+            };
+
+            records
+                .iter()
+                .map(|r| {
+                    vec![
+                        kind.into(),
+                        r.timestamp.to_rfc3339(),
+                        r.room.clone(),
+                        r.from.clone(),
+                        r.text.clone(),
+                    ]
+                })
+                .collect()
+        }
+        Message::Rooms { rooms } => rooms
+            .iter()
+            .map(|r| vec!["room".into(), r.name.clone(), r.members.to_string()])
+            .collect(),
+        Message::Shutdown { reason, .. } => vec![vec!["shutdown".into(), reason.clone()]],
+        _ => vec![],
+    };
+
+    for line in fields {
+        let line: Vec<String> = line.iter().map(|f| escape(f)).collect();
+        println!("{}", line.join("\t"));
+    }
+}
+
+fn escape(field: &str) -> String {
+    field
+        .replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
@@ -1,336 +1,1553 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
 use std::io::{self, ErrorKind};
-use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::PathBuf;
 use std::string::String;
-use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::*;
-use std::thread::{self, JoinHandle};
-use std::time::Duration;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
+use mio::event::Event;
+use mio::{Events, Interest, Poll, Registry, Token, Waker};
+
+use crate::accounts::{self, Accounts};
 use crate::common::{
-    self, read_messages, read_to_string, send_string, setup_stream, Action, ServerError,
+    is_guest, negotiate_features, negotiate_version, read_messages, validate_room,
+    validate_username, Action, Connection, ErrorCode, Feature, HandshakeError, Message,
+    OverflowPolicy, RoomInfo, ServerError, DEFAULT_HEARTBEAT, DEFAULT_HISTORY_DEPTH,
+    DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_FRAME_SIZE, DEFAULT_MAX_QUEUE, DEFAULT_ROOM, GUEST_PREFIX,
 };
+use crate::dispatch::{CommandContext, Dispatcher, Handler};
+use crate::history::{fit_records, History};
+use crate::transport::{self, Endpoint, Evented, Listener, Transport};
+
+/// Protocol extensions this server knows how to handle.
+const SERVER_FEATURES: &[Feature] = &[Feature::History, Feature::Rooms, Feature::Users];
+
+/// How many usernames a client may try before it gets disconnected.
+const MAX_USERNAME_ATTEMPTS: usize = 5;
+
+/// Readiness of any of the listening sockets, meaning someone wants to come
+/// in.
+const BUTTLER: Token = Token(0);
+
+/// Used by other threads to get the event loop to look at the action queue.
+const WAKER: Token = Token(1);
+
+/// Tokens for connections are handed out starting from here.
+const FIRST_CONNECTION: usize = 2;
+
+/// How long to wait for queued messages to go out when shutting down.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How far a connection got into the handshake.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Hello,
+    /// Picking a name, `checking` while the password is looked at.
+    Username {
+        attempts: usize,
+        checking: bool,
+    },
+    Joined,
+}
+
+/// Knobs the server is started with.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub max_frame_size: usize,
+    /// How many messages may wait for a single user before
+    /// `overflow_policy` kicks in.
+    pub max_queue: usize,
+    pub overflow_policy: OverflowPolicy,
+    /// How long users are given to wrap up once the server is asked to stop.
+    pub grace: Duration,
+    /// Quiet users get pinged this often.
+    pub heartbeat: Duration,
+    /// Users that stay silent for this long, not even answering pings, are
+    /// dropped.
+    pub idle_timeout: Duration,
+    /// Where history is kept. Without one it's lost when the server stops.
+    pub history_file: Option<PathBuf>,
+    /// How many past lines a user gets to see right after joining.
+    pub history_depth: usize,
+    /// Where registered usernames are kept. Without one registrations are
+    /// lost when the server stops.
+    pub accounts_file: Option<PathBuf>,
+    /// Connections are wrapped in TLS when set.
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_queue: DEFAULT_MAX_QUEUE,
+            overflow_policy: OverflowPolicy::DropOldest,
+            grace: Duration::ZERO,
+            heartbeat: DEFAULT_HEARTBEAT,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            history_file: None,
+            history_depth: DEFAULT_HISTORY_DEPTH,
+            accounts_file: None,
+            tls: None,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct User {
     name: String,
-    stream: Box<TcpStream>,
+    conn: Box<Connection<Box<dyn Evented>>>,
+    stage: Stage,
+    overflow_policy: OverflowPolicy,
+    /// Messages that never made it to this user because it fell behind.
+    dropped: u64,
+    /// Set once sending to this user failed, so nobody tries again while
+    /// it's waiting to be dropped.
+    failed: bool,
+    rooms: HashSet<String>,
+    /// Last time anything came in from this user.
+    last_seen: Instant,
+    /// What was agreed on during the handshake.
+    features: Vec<Feature>,
 }
 
 impl User {
-    fn new(login: String, stream: Box<TcpStream>) -> Self {
+    fn new(conn: Box<Connection<Box<dyn Evented>>>, overflow_policy: OverflowPolicy) -> Self {
         User {
-            name: login,
-            stream,
+            name: String::new(),
+            conn,
+            stage: Stage::Hello,
+            overflow_policy,
+            dropped: 0,
+            failed: false,
+            rooms: HashSet::new(),
+            last_seen: Instant::now(),
+            features: vec![],
+        }
+    }
+
+    /// Queues `msg` and writes as much of it as the socket takes right now.
+    /// Whatever is left goes out once the socket is writable again, so a slow
+    /// user only ever holds up its own queue.
+    fn send(&mut self, msg: &Message) -> Result<(), ServerError> {
+        match self.conn.queue_message(msg) {
+            Err(ServerError::QueueFull) if self.overflow_policy == OverflowPolicy::DropOldest => {
+                self.dropped += 1;
+
+                // When the only frame left is half written, it's the new
+                // one that has to go
+                if self.conn.drop_oldest() {
+                    self.conn.queue_message(msg)?;
+                }
+            }
+            result => result?,
         }
+
+        self.conn.flush_pending()?;
+
+        Ok(())
     }
 }
 
-pub fn start(addr: SocketAddr) -> Result<(), ServerError> {
-    println!("Starting server @ {}", addr);
+/// A named group of users. Chat sent to a room only reaches its members.
+#[derive(Debug, Default)]
+pub struct Room {
+    pub members: HashSet<String>,
+    pub topic: Option<String>,
+}
+
+/// State owned by the event loop. Only the loop's thread ever touches it, so
+/// there's no locking involved; other threads talk to it through `Action`s.
+struct EventLoop {
+    registry: Registry,
+    buttler: Vec<Listener>,
+    users: HashMap<Token, User>,
+    rooms: HashMap<String, Room>,
+    sender: Sender<Action>,
+    next_token: usize,
+    config: ServerConfig,
+    commands: Dispatcher,
+    history: History,
+    accounts: Accounts,
+    /// Hashing and checking passwords takes a while, so it's done elsewhere.
+    passwords: Sender<PasswordJob>,
+    started: Instant,
+    /// Set once shutting down, to when connections get closed.
+    closing_at: Option<Instant>,
+    next_heartbeat: Instant,
+    /// Messages dropped for users that already left.
+    dropped_messages: u64,
+    /// Connections with more to read than they got to last time.
+    unread: HashSet<Token>,
+}
 
-    let tcp_listener = TcpListener::bind(addr).expect("Failed to bind.");
+/// Puts a server together. Nothing is bound until `build`.
+pub struct ServerBuilder {
+    endpoints: Vec<Endpoint>,
+    config: ServerConfig,
+    commands: Dispatcher,
+}
 
-    serve(tcp_listener)?;
+impl ServerBuilder {
+    pub fn new() -> Self {
+        ServerBuilder {
+            endpoints: vec![],
+            config: ServerConfig::default(),
+            commands: Dispatcher::default(),
+        }
+    }
 
-    Ok(())
+    /// Listens on `endpoint` as well. Port 0 picks a free one, which
+    /// `Server::endpoints` tells.
+    pub fn bind(mut self, endpoint: Endpoint) -> Self {
+        self.endpoints.push(endpoint);
+        self
+    }
+
+    /// Sets every knob at once.
+    pub fn config(mut self, config: ServerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.config.max_frame_size = max_frame_size;
+        self
+    }
+
+    pub fn max_queue(mut self, max_queue: usize, overflow_policy: OverflowPolicy) -> Self {
+        self.config.max_queue = max_queue;
+        self.config.overflow_policy = overflow_policy;
+        self
+    }
+
+    pub fn grace(mut self, grace: Duration) -> Self {
+        self.config.grace = grace;
+        self
+    }
+
+    pub fn heartbeat(mut self, heartbeat: Duration, idle_timeout: Duration) -> Self {
+        self.config.heartbeat = heartbeat;
+        self.config.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn history(mut self, file: Option<PathBuf>, depth: usize) -> Self {
+        self.config.history_file = file;
+        self.config.history_depth = depth;
+        self
+    }
+
+    pub fn accounts(mut self, file: Option<PathBuf>) -> Self {
+        self.config.accounts_file = file;
+        self
+    }
+
+    pub fn tls(mut self, tls: Arc<rustls::ServerConfig>) -> Self {
+        self.config.tls = Some(tls);
+        self
+    }
+
+    /// Adds `/name`, replacing the built-in one if there's one.
+    pub fn command(mut self, name: &'static str, handler: Handler) -> Self {
+        self.commands.register(name, handler);
+        self
+    }
+
+    /// Binds every endpoint, ready to `run`.
+    pub fn build(self) -> Result<Server, ServerError> {
+        let poll = Poll::new()?;
+        let waker = Arc::new(Waker::new(poll.registry(), WAKER)?);
+        let (sender, actions) = channel::<Action>();
+
+        let mut listeners = vec![];
+        for endpoint in self.endpoints.iter() {
+            listeners.push(Listener::bind(endpoint)?);
+        }
+
+        Ok(Server {
+            listeners: create_buttler(listeners, poll.registry())?,
+            config: self.config,
+            commands: self.commands,
+            poll,
+            handle: Handle { sender, waker },
+            actions,
+        })
+    }
 }
 
-fn serve(listener: TcpListener) -> Result<(), ServerError> {
-    let running = Arc::new(AtomicBool::new(true));
-    let running_clone = running.clone();
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-    ctrlc::set_handler(move || {
-        running_clone.store(false, Ordering::SeqCst);
-    })
-    .expect("Failed to set ctrl-c handler");
+/// A server that's bound and ready to `run`.
+pub struct Server {
+    listeners: Vec<Listener>,
+    config: ServerConfig,
+    commands: Dispatcher,
+    poll: Poll,
+    handle: Handle,
+    actions: Receiver<Action>,
+}
 
-    let mut users = Arc::new(RwLock::new(Vec::<User>::new()));
+/// Lets other threads talk to a running server.
+#[derive(Debug, Clone)]
+pub struct Handle {
+    sender: Sender<Action>,
+    waker: Arc<Waker>,
+}
 
-    let (action_sender, action_receiver) = channel::<Action>();
+impl Handle {
+    /// Asks the server to stop, telling everyone `reason`. Asking again skips
+    /// whatever is left of the grace period.
+    pub fn shutdown(&self, reason: &str) -> Result<(), ServerError> {
+        self.sender.send(Action::Shutdown {
+            reason: reason.into(),
+        })?;
+        self.waker.wake()?;
 
-    let buttler_running = running.clone();
-    let buttler_sender = action_sender.clone();
-    let buttler =
-        create_buttler(listener, buttler_sender, buttler_running).expect("Initialize buttler");
+        Ok(())
+    }
 
-    let writter_sender = action_sender.clone();
-    let writter = create_action_processor(action_receiver, writter_sender, users.clone())?;
+    /// Connects to the server in memory, skipping the listeners. Handy for
+    /// tests, and for tools the server is embedded in.
+    pub fn connect(&self) -> Result<Box<dyn Transport>, ServerError> {
+        let (server, client) = transport::pair()?;
+        self.sender.send(Action::Connect(Box::new(server)))?;
+        self.waker.wake()?;
 
-    let mut serve_sender = action_sender.clone();
+        Ok(Box::new(client))
+    }
+}
+
+impl Server {
+    /// Where the server listens, with any port 0 replaced by the one picked.
+    pub fn endpoints(&self) -> Vec<Endpoint> {
+        self.listeners
+            .iter()
+            .filter_map(|l| l.endpoint().ok())
+            .collect()
+    }
 
-    let mut err = None;
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Serves everyone that connects until shut down through a `Handle`.
+    pub fn run(self) -> Result<(), ServerError> {
+        for endpoint in self.endpoints() {
+            println!("Starting server @ {}", endpoint);
+        }
 
-    while running.load(Ordering::SeqCst) {
-        serve_chat(&mut users, &mut serve_sender).unwrap_or_else(|e| err = Some(e))
+        serve(self)
+    }
+}
+
+fn serve(server: Server) -> Result<(), ServerError> {
+    let Server {
+        listeners,
+        config,
+        commands,
+        mut poll,
+        handle,
+        actions: action_receiver,
+    } = server;
+
+    let history = match config.history_file.as_ref() {
+        Some(path) => History::open(path)?,
+        None => History::in_memory(),
+    };
+
+    let accounts = match config.accounts_file.as_ref() {
+        Some(path) => Accounts::open(path)?,
+        None => Accounts::in_memory(),
+    };
+
+    let passwords = spawn_password_worker(handle.sender.clone(), handle.waker.clone())?;
+
+    let mut server = EventLoop {
+        registry: poll.registry().try_clone()?,
+        buttler: listeners,
+        users: HashMap::new(),
+        rooms: HashMap::new(),
+        sender: handle.sender,
+        next_token: FIRST_CONNECTION,
+        next_heartbeat: Instant::now() + config.heartbeat,
+        config,
+        commands,
+        history,
+        accounts,
+        passwords,
+        started: Instant::now(),
+        closing_at: None,
+        dropped_messages: 0,
+        unread: HashSet::new(),
+    };
+
+    let mut events = Events::with_capacity(1024);
+
+    loop {
+        let wake_at = match server.closing_at {
+            Some(at) => at.min(server.next_heartbeat),
+            None => server.next_heartbeat,
+        };
+        // Whoever has more to read shouldn't wait for the next event
+        let timeout = match server.unread.is_empty() {
+            true => Some(wake_at.saturating_duration_since(Instant::now())),
+            false => Some(Duration::ZERO),
+        };
+
+        if let Err(e) = poll.poll(&mut events, timeout) {
+            if e.kind() == ErrorKind::Interrupted {
+                continue;
+            }
+
+            return Err(ServerError::from(e));
+        }
+
+        for event in events.iter() {
+            match event.token() {
+                BUTTLER => server.receive_new_connections(),
+                WAKER => (),
+                token => server.serve_chat(token, event),
+            }
+        }
+
+        for token in std::mem::take(&mut server.unread) {
+            server.receive(token);
+        }
+
+        if server.next_heartbeat <= Instant::now() {
+            server.heartbeat();
+        }
+
+        if !server.process_actions(&action_receiver) {
+            break;
+        }
+
+        if server.closing_at.is_some_and(|at| at <= Instant::now()) {
+            break;
+        }
     }
 
     println!("Shutting down main...");
+    server.flush_all();
 
-    action_sender
-        .send(Action::Shutdown)
-        .expect("Could not shutdown writter thread");
+    let users: Vec<User> = server.users.drain().map(|(_, user)| user).collect();
+    for user in users {
+        server.close(user);
+    }
 
-    buttler.join().expect("Failed to shutdown");
-    writter.join().expect("Failed to shutdown");
+    if server.dropped_messages > 0 {
+        println!(
+            "INFO: {} messages were dropped for slow users",
+            server.dropped_messages
+        );
+    }
 
     Ok(())
 }
 
-fn serve_chat(
-    users: &mut Arc<RwLock<Vec<User>>>,
-    sender: &mut Sender<Action>,
-) -> Result<(), ServerError> {
-    if let Ok(mut write_lock) = users.try_write() {
-        for user in write_lock.iter_mut() {
-            let stream = user.stream.as_mut();
-
-            match read_messages(stream) {
-                Ok(None) => continue,
-                Ok(Some(messages)) => {
-                    for message in messages {
-                        sender
-                            .send(Action::Broadcast {
-                                message,
-                                username: user.name.clone(),
-                            })
-                            .expect(&format!("Failed to broadcast message"));
+/// All listeners share the same token, so whenever it comes up every one of
+/// them is asked for new connections.
+fn create_buttler(mut listeners: Vec<Listener>, registry: &Registry) -> io::Result<Vec<Listener>> {
+    for listener in listeners.iter_mut() {
+        registry.register(listener, BUTTLER, Interest::READABLE)?;
+    }
+
+    Ok(listeners)
+}
+
+impl EventLoop {
+    fn receive_new_connections(&mut self) {
+        for i in 0..self.buttler.len() {
+            loop {
+                match self.buttler[i].accept(self.config.tls.as_ref()) {
+                    Ok(stream) => self.receive_new_connection(stream).unwrap_or_else(|e| {
+                        eprintln!("ERROR: {:?}", e);
+                    }),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => {
+                        println!("Error connecting new listener: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Starts talking to a client over `stream`, whatever it runs over.
+    fn receive_new_connection(&mut self, mut stream: Box<dyn Evented>) -> Result<(), ServerError> {
+        if self.closing_at.is_some() {
+            // Not worth letting anyone in while we're on our way out
+            return Ok(());
+        }
+
+        let token = Token(self.next_token);
+        self.next_token += 1;
+
+        self.registry
+            .register(&mut stream, token, Interest::READABLE | Interest::WRITABLE)?;
+
+        let conn = Connection::new(stream, self.config.max_frame_size)
+            .with_max_queue(self.config.max_queue);
+        let user = User::new(Box::new(conn), self.config.overflow_policy);
+        self.users.insert(token, user);
+
+        Ok(())
+    }
+
+    fn serve_chat(&mut self, token: Token, event: &Event) {
+        let user = match self.users.get_mut(&token) {
+            Some(user) => user,
+            None => return,
+        };
+
+        if event.is_writable() {
+            if let Err(e) = user.conn.flush_pending() {
+                self.disconnect(token, Box::new(e));
+                return;
+            }
+        }
+
+        if event.is_readable() {
+            self.receive(token);
+        }
+    }
+
+    /// Reads and handles what `token` sent, up to a limit. Whatever is left
+    /// gets its turn on the next pass of the loop, after everyone else's.
+    fn receive(&mut self, token: Token) {
+        let user = match self.users.get_mut(&token) {
+            Some(user) => user,
+            None => return,
+        };
+
+        let messages = match read_messages(&mut user.conn) {
+            Ok(messages) => {
+                user.last_seen = Instant::now();
+                messages.unwrap_or_default()
+            }
+            Err(e) => {
+                self.disconnect(token, e);
+                return;
+            }
+        };
+        if user.conn.has_unread() {
+            self.unread.insert(token);
+        }
+
+        for message in messages {
+            if let Err(e) = self.handle_message(token, message) {
+                self.disconnect(token, Box::new(e));
+                return;
+            }
+        }
+
+        // The peer may have hung up right after its last message
+        if self.users.get(&token).is_some_and(|u| u.conn.is_closed()) {
+            self.disconnect(token, Box::new(ServerError::UserShutdown));
+        }
+    }
+
+    fn handle_message(&mut self, token: Token, message: Message) -> Result<(), ServerError> {
+        let user = match self.users.get_mut(&token) {
+            Some(user) => user,
+            None => return Ok(()),
+        };
+
+        match (user.stage, message) {
+            (Stage::Hello, Message::Hello { version, features }) => {
+                handshake_client(user, version, &features)
+            }
+            (
+                Stage::Username {
+                    attempts,
+                    checking: false,
+                },
+                Message::Join { username, password },
+            ) => self.get_user(token, attempts, username, password),
+            (Stage::Joined, message) => {
+                self.chat(token, message);
+                Ok(())
+            }
+            (_, _) => {
+                reject(user, ErrorCode::UnexpectedMessage, "Unexpected message");
+                Err(ServerError::FailedHandshake(
+                    HandshakeError::UnexpectedMessage,
+                ))
+            }
+        }
+    }
+
+    fn get_user(
+        &mut self,
+        token: Token,
+        attempts: usize,
+        username: String,
+        password: Option<String>,
+    ) -> Result<(), ServerError> {
+        let check = check_username(&username, &self.users)
+            .and_then(|()| authenticate(&username, password, &self.accounts));
+
+        match check {
+            Ok(Some((hash, password))) => {
+                let user = self.users.get_mut(&token).expect("User to be connected");
+                user.stage = Stage::Username {
+                    attempts,
+                    checking: true,
+                };
+                self.passwords
+                    .send(PasswordJob::Verify {
+                        token,
+                        username,
+                        password,
+                        hash,
+                    })
+                    .expect("Password worker to be running");
+                Ok(())
+            }
+            Ok(None) => self.admit(token, attempts, username, Ok(())),
+            Err(e) => self.admit(token, attempts, username, Err(e)),
+        }
+    }
+
+    /// Lets `token` in as `username` if `check` passed, or has it try again
+    /// until it runs out of attempts.
+    fn admit(
+        &mut self,
+        token: Token,
+        attempts: usize,
+        username: String,
+        check: Result<(), (ErrorCode, String)>,
+    ) -> Result<(), ServerError> {
+        let user = self.users.get_mut(&token).expect("User to be connected");
+
+        match check {
+            Ok(()) => {
+                user.name = username.clone();
+                user.stage = Stage::Joined;
+                user.send(&Message::Join {
+                    username: username.clone(),
+                    password: None,
+                })?;
+                self.sender.send(Action::NewUser { username })?;
+            }
+            Err((code, reason)) => {
+                reject(user, code, &reason);
+
+                if attempts + 1 >= MAX_USERNAME_ATTEMPTS {
+                    let error = match code {
+                        ErrorCode::AuthenticationFailed => {
+                            HandshakeError::AuthenticationFailed(reason)
+                        }
+                        _ => HandshakeError::InvalidUsername(reason),
+                    };
+                    return Err(ServerError::FailedHandshake(error));
+                }
+
+                user.stage = Stage::Username {
+                    attempts: attempts + 1,
+                    checking: false,
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    fn chat(&mut self, token: Token, message: Message) {
+        let user = match self.users.get_mut(&token) {
+            Some(user) => user,
+            None => return,
+        };
+
+        match message {
+            Message::Chat { room, text, .. } => {
+                let room = room.unwrap_or_else(|| DEFAULT_ROOM.to_owned());
+
+                self.sender
+                    .send(Action::Broadcast {
+                        message: text,
+                        room,
+                        username: user.name.clone(),
+                    })
+                    .expect("Failed to broadcast message")
+            }
+            Message::Direct { to, text, .. } => self
+                .sender
+                .send(Action::Direct {
+                    from: user.name.clone(),
+                    to,
+                    message: text,
+                })
+                .expect("Failed to send direct message"),
+            Message::JoinRoom { room, .. } => {
+                if let Err(reason) = validate_room(&room) {
+                    reject(user, ErrorCode::InvalidRoom, &reason);
+                    return;
+                }
+
+                self.sender
+                    .send(Action::EnterRoom {
+                        username: user.name.clone(),
+                        room,
+                    })
+                    .expect("Failed to join room")
+            }
+            Message::PartRoom { room, .. } => self
+                .sender
+                .send(Action::LeaveRoom {
+                    username: user.name.clone(),
+                    room,
+                })
+                .expect("Failed to leave room"),
+            Message::Command { name, args, room } => {
+                let caller = user.name.clone();
+                let room = room.unwrap_or_else(|| DEFAULT_ROOM.to_owned());
+                self.run_command(token, &caller, &name, &args, &room);
+            }
+            Message::ListRooms => {
+                let rooms = list_rooms(&self.rooms);
+                user.send(&Message::Rooms { rooms }).unwrap_or_else(|e| {
+                    eprintln!("ERROR: Failed to answer {}: {:?}", &user.name, e)
+                })
+            }
+            Message::ListUsers => {
+                let users = list_users(&self.users)
+                    .into_iter()
+                    .map(String::from)
+                    .collect();
+
+                if let Some(user) = self.users.get_mut(&token) {
+                    user.send(&Message::Users { users }).unwrap_or_else(|e| {
+                        eprintln!("ERROR: Failed to answer {}: {:?}", &user.name, e)
+                    })
+                }
+            }
+            Message::Goodbye { reason, .. } => self
+                .sender
+                .send(Action::Goodbye(user.name.clone(), reason))
+                .expect("Failed to gracefully shutdown user"),
+            Message::Ping => user
+                .send(&Message::Pong)
+                .unwrap_or_else(|e| eprintln!("ERROR: Failed to answer {}: {:?}", &user.name, e)),
+            Message::Pong => (),
+            other => {
+                eprintln!("WARN: Unexpected message from {}: {:?}", &user.name, other);
+                reject(user, ErrorCode::UnexpectedMessage, "Unexpected message");
+            }
+        }
+    }
+
+    /// Pings users that have been quiet for a while and drops the ones that
+    /// stopped answering, which is the only way to notice a peer that's gone
+    /// without closing its connection.
+    fn heartbeat(&mut self) {
+        let now = Instant::now();
+        self.next_heartbeat = now + self.config.heartbeat;
+
+        let mut timed_out = vec![];
+        for (token, user) in self.users.iter_mut().filter(|(_, u)| !u.failed) {
+            let idle = now.duration_since(user.last_seen);
+
+            if idle >= self.config.idle_timeout {
+                timed_out.push(*token);
+            } else if idle >= self.config.heartbeat && user.stage == Stage::Joined {
+                user.send(&Message::Ping)
+                    .unwrap_or_else(|e| eprintln!("ERROR: Failed to ping {}: {:?}", &user.name, e));
+            }
+        }
+
+        for token in timed_out {
+            let e = ServerError::TimedOut(self.config.idle_timeout);
+            self.disconnect(token, Box::new(e));
+        }
+    }
+
+    /// Gets rid of a connection that failed or hung up. Users that already
+    /// joined leave through an action, so everyone else hears about it.
+    fn disconnect(&mut self, token: Token, e: Box<dyn Error>) {
+        let user = match self.users.get(&token) {
+            Some(user) => user,
+            None => return,
+        };
+
+        if user.stage != Stage::Joined {
+            if let Some(user) = self.users.remove(&token) {
+                eprintln!("ERROR: Could not handshake with {:?}: {}", token, e);
+                self.close(user);
+            }
+            return;
+        }
+
+        let action = match e.downcast_ref::<ServerError>() {
+            Some(ServerError::UserShutdown) => Action::Goodbye(user.name.clone(), None),
+            _ => {
+                eprintln!("ERROR: Dropping {}: {}", &user.name, e);
+                Action::Dropped(user.name.clone())
+            }
+        };
+
+        self.sender.send(action).expect("Failed to disconnect user");
+    }
+
+    /// Runs every queued action. Returns false once the server should stop.
+    fn process_actions(&mut self, receiver: &Receiver<Action>) -> bool {
+        while let Ok(action) = receiver.try_recv() {
+            match action {
+                Action::Goodbye(name, reason) => {
+                    if let Ok(user) = self.users.delete_user(&name) {
+                        self.close(user);
+                        println!("Bye bye {}!", name);
+                        self.announce(&name, goodbye(&name, reason));
+                    }
+                }
+                Action::Dropped(name) => {
+                    if let Ok(user) = self.users.delete_user(&name) {
+                        self.close(user);
+                        println!("INFO: Disconnecting dropped user: {}!", name);
+                        let reason = Some("Connection dropped".to_owned());
+                        self.announce(&name, goodbye(&name, reason));
                     }
                 }
-                Err(e) => {
-                    if e.is::<ServerError>() {
-                        let err = (*e).downcast_ref::<ServerError>().unwrap();
-                        if let ServerError::UserShutdown = err {
-                            break;
+                Action::Connect(stream) => {
+                    if let Err(e) = self.receive_new_connection(stream) {
+                        eprintln!("ERROR: Could not take connection: {}", e);
+                    }
+                }
+                Action::Shutdown { reason } => {
+                    if self.closing_at.is_some() {
+                        // Asked again, so stop waiting for the grace period
+                        return false;
+                    }
+
+                    println!("INFO: Shutting down: {}", &reason);
+                    let grace = self.config.grace;
+                    let notice = Message::Shutdown {
+                        reason,
+                        grace: (!grace.is_zero()).then_some(grace.as_secs()),
+                    };
+                    self.deliver(&notice, |_| true);
+
+                    if grace.is_zero() {
+                        return false;
+                    }
+                    self.closing_at = Some(Instant::now() + grace);
+                }
+                Action::Broadcast {
+                    username,
+                    room,
+                    message: msg,
+                } => {
+                    if !self.check_member(&username, &room) {
+                        continue;
+                    }
+
+                    let chat = Message::Chat {
+                        from: username.clone(),
+                        room: Some(room.clone()),
+                        text: msg.clone(),
+                    };
+                    if !self.fits(&username, &chat) {
+                        continue;
+                    }
+
+                    println!("[{}] {}: {}", &room, &username, &msg);
+                    self.history
+                        .append(&username, &room, &msg)
+                        .unwrap_or_else(|e| eprintln!("ERROR: Failed to keep history: {}", e));
+                    self.announce_room(&room, Some(&username), chat);
+                }
+                Action::Direct { from, to, message } => {
+                    let direct = Message::Direct {
+                        from: from.clone(),
+                        to: to.clone(),
+                        text: message,
+                    };
+                    if !self.fits(&from, &direct) {
+                        continue;
+                    }
+
+                    match self.users.find_by_username_mut(&to) {
+                        Some(user) if !user.failed => {
+                            if let Err(e) = user.send(&direct) {
+                                eprintln!("ERROR: Failed to message {}: {:?}", &to, e);
+                                user.failed = true;
+                                self.sender
+                                    .send(Action::Dropped(to))
+                                    .expect("Failed to drop user");
+                            }
+                        }
+                        _ => {
+                            if let Some(user) = self.users.find_by_username_mut(&from) {
+                                let reason = format!("{} is not connected", &to);
+                                reject(user, ErrorCode::NoSuchUser, &reason);
+                            }
                         }
                     }
+                }
+                Action::Register {
+                    from,
+                    username,
+                    password,
+                } => {
+                    if let Err(reason) = self.accounts.check_new(&username, &password) {
+                        if let Some(user) = self.users.find_by_username_mut(&from) {
+                            reject(user, ErrorCode::CommandFailed, &reason);
+                        }
+                        continue;
+                    }
 
-                    let err = (*e).downcast_ref::<io::Error>().unwrap();
-                    let kind = err.kind();
+                    self.passwords
+                        .send(PasswordJob::Hash {
+                            from,
+                            username,
+                            password,
+                        })
+                        .expect("Password worker to be running");
+                }
+                Action::Hashed {
+                    from,
+                    username,
+                    hash,
+                } => {
+                    let result = hash.and_then(|hash| self.accounts.add(&username, hash));
+                    let user = match self.users.find_by_username_mut(&from) {
+                        Some(user) => user,
+                        None => continue,
+                    };
 
-                    if kind == ErrorKind::BrokenPipe {
-                        sender
-                            .send(Action::Goodbye(user.name.clone()))
-                            .expect("Failed to gracefully shutdown user thread");
-                        break;
+                    match result {
+                        Ok(()) => {
+                            println!("INFO: {} registered {}", &from, &username);
+                            let text = format!("{} is now registered", &username);
+                            user.send(&Message::System { text }).unwrap_or_else(|e| {
+                                eprintln!("ERROR: Failed to answer {}: {:?}", &from, e)
+                            });
+
+                            if from != username {
+                                self.sender
+                                    .send(Action::Rename { from, to: username })
+                                    .expect("Failed to rename user");
+                            }
+                        }
+                        Err(reason) => reject(user, ErrorCode::CommandFailed, &reason),
                     }
+                }
+                Action::Verified {
+                    token,
+                    username,
+                    valid,
+                } => {
+                    let attempts = match self.users.get(&token).map(|u| u.stage) {
+                        Some(Stage::Username {
+                            attempts,
+                            checking: true,
+                        }) => attempts,
+                        // Gone while the password was checked
+                        _ => continue,
+                    };
 
-                    if kind == ErrorKind::TimedOut
-                        || kind == ErrorKind::Interrupted
-                        || kind == ErrorKind::WouldBlock
-                    {
+                    // The name may have been taken in the meantime
+                    let check = match valid {
+                        true => check_username(&username, &self.users),
+                        false => Err((
+                            ErrorCode::AuthenticationFailed,
+                            format!("Wrong password for {}", username),
+                        )),
+                    };
+                    if let Err(e) = self.admit(token, attempts, username, check) {
+                        self.disconnect(token, Box::new(e));
+                    }
+                }
+                Action::Rename { from, to } => {
+                    if self.users.has_user(&to) {
+                        if let Some(user) = self.users.find_by_username_mut(&from) {
+                            let reason = format!("{} is taken", &to);
+                            reject(user, ErrorCode::UsernameTaken, &reason);
+                        }
                         continue;
                     }
 
-                    println!("Err: [{:?}] {:?}", kind, &err);
-                    continue;
+                    let rooms = match self.users.find_by_username_mut(&from) {
+                        Some(user) => {
+                            user.name = to.clone();
+                            user.rooms.clone()
+                        }
+                        None => continue,
+                    };
+
+                    for room in rooms.iter() {
+                        if let Some(r) = self.rooms.get_mut(room) {
+                            r.members.remove(&from);
+                            r.members.insert(to.clone());
+                        }
+                    }
+
+                    println!("INFO: {} is now {}", &from, &to);
+                    self.deliver(&Message::Renamed { from, to }, |_| true);
+                }
+                Action::Emote {
+                    username,
+                    room,
+                    message,
+                } => {
+                    if !self.check_member(&username, &room) {
+                        continue;
+                    }
+
+                    let emote = Message::Emote {
+                        from: username.clone(),
+                        room: room.clone(),
+                        text: message.clone(),
+                    };
+                    if !self.fits(&username, &emote) {
+                        continue;
+                    }
+
+                    println!("[{}] * {} {}", &room, &username, &message);
+                    self.announce_room(&room, None, emote);
+                }
+                Action::SetTopic {
+                    username,
+                    room,
+                    topic,
+                } => {
+                    if !self.check_member(&username, &room) {
+                        continue;
+                    }
+
+                    let text = format!(
+                        "{} changed the topic of {} to: {}",
+                        &username, &room, &topic
+                    );
+                    println!("INFO: {}", &text);
+
+                    if let Some(r) = self.rooms.get_mut(&room) {
+                        r.topic = Some(topic);
+                        self.announce_room(&room, None, Message::System { text });
+                    }
+                }
+                Action::EnterRoom { username, room } => {
+                    let joined = Message::JoinRoom {
+                        username: username.clone(),
+                        room: room.clone(),
+                    };
+
+                    if self.enter_room(&username, &room) {
+                        println!("INFO: {} joined {}", &username, &room);
+                        self.announce_room(&room, None, joined);
+
+                        let topic = self.rooms.get(&room).and_then(|r| r.topic.clone());
+                        if let (Some(topic), Some(user)) =
+                            (topic, self.users.find_by_username_mut(&username))
+                        {
+                            let text = format!("Topic for {}: {}", &room, topic);
+                            user.send(&Message::System { text }).unwrap_or_else(|e| {
+                                eprintln!("ERROR: Failed to answer {}: {:?}", &username, e)
+                            });
+                        }
+                    } else if let Some(user) = self.users.find_by_username_mut(&username) {
+                        // Already there, but the client still wants to
+                        // hear back to switch to it
+                        user.send(&joined).unwrap_or_else(|e| {
+                            eprintln!("ERROR: Failed to answer {}: {:?}", &username, e)
+                        });
+                    }
+                }
+                Action::LeaveRoom { username, room } => {
+                    if !self.check_member(&username, &room) {
+                        continue;
+                    }
+
+                    let parted = Message::PartRoom {
+                        username: username.clone(),
+                        room: room.clone(),
+                    };
+
+                    // Announced first so the one leaving hears about it too
+                    self.announce_room(&room, None, parted);
+
+                    if let Some(user) = self.users.find_by_username_mut(&username) {
+                        user.rooms.remove(&room);
+                    }
+                    self.forget_member(&room, &username);
+                    println!("INFO: {} left {}", &username, &room);
+                }
+                Action::NewUser { username } => {
+                    greet_user(&username);
+                    self.enter_room(&username, DEFAULT_ROOM);
+                    self.replay_history(&username);
+                    let join = Message::Join {
+                        username: username.clone(),
+                        password: None,
+                    };
+                    self.announce(&username, join);
                 }
             }
         }
+
+        true
     }
 
-    thread::sleep(Duration::from_micros(5));
-    thread::yield_now();
+    /// Runs `/name` through the dispatcher and sends whatever it has to
+    /// say back to the user at `token`.
+    fn run_command(&mut self, token: Token, caller: &str, name: &str, args: &[String], room: &str) {
+        let users = list_users(&self.users);
+
+        let features = self
+            .users
+            .get(&token)
+            .map_or(&[][..], |u| u.features.as_slice());
+
+        let mut ctx = CommandContext {
+            caller,
+            features,
+            room,
+            args,
+            users,
+            rooms: &self.rooms,
+            history: &self.history,
+            accounts: &self.accounts,
+            started: self.started,
+            max_frame_size: self.config.max_frame_size,
+            actions: &self.sender,
+            replies: vec![],
+        };
+        let result = self.commands.dispatch(name, &mut ctx);
+        let replies = ctx.replies;
+
+        let user = match self.users.get_mut(&token) {
+            Some(user) => user,
+            None => return,
+        };
+
+        for reply in replies.iter() {
+            user.send(reply)
+                .unwrap_or_else(|e| eprintln!("ERROR: Failed to answer {}: {:?}", &user.name, e));
+        }
+
+        match result {
+            Some(Ok(())) => (),
+            Some(Err(reason)) => reject(user, ErrorCode::CommandFailed, &reason),
+            None => {
+                let reason = format!("Unknown command /{}", name);
+                reject(user, ErrorCode::UnknownCommand, &reason);
+            }
+        }
+    }
+
+    /// Catches a newcomer up on what was said lately, if its client can take
+    /// it.
+    fn replay_history(&mut self, username: &str) {
+        let records = self.history.last(DEFAULT_ROOM, self.config.history_depth);
+        if records.is_empty() {
+            return;
+        }
+
+        if let Some(user) = self.users.find_by_username_mut(username) {
+            if !user.features.contains(&Feature::History) {
+                return;
+            }
+
+            let replay = fit_records(records, self.config.max_frame_size, |records| {
+                Message::History {
+                    room: DEFAULT_ROOM.into(),
+                    records,
+                }
+            });
+            for msg in replay {
+                user.send(&msg).unwrap_or_else(|e| {
+                    eprintln!("ERROR: Failed to send history to {}: {:?}", username, e)
+                });
+            }
+        }
+    }
+
+    /// Puts `username` in `room`, creating the room if needed. Returns false
+    /// if it was there already.
+    fn enter_room(&mut self, username: &str, room: &str) -> bool {
+        let user = match self.users.find_by_username_mut(username) {
+            Some(user) => user,
+            None => return false,
+        };
+
+        if !user.rooms.insert(room.to_owned()) {
+            return false;
+        }
+
+        self.rooms
+            .entry(room.to_owned())
+            .or_default()
+            .members
+            .insert(username.to_owned());
+
+        true
+    }
+
+    /// Whether `username` is in `room`, telling them off if not. Done while
+    /// processing actions rather than on arrival, so a room joined right
+    /// before counts.
+    fn check_member(&mut self, username: &str, room: &str) -> bool {
+        let user = match self.users.find_by_username_mut(username) {
+            Some(user) => user,
+            None => return false,
+        };
+
+        if user.rooms.contains(room) {
+            return true;
+        }
+
+        let reason = format!("You're not in {}", room);
+        reject(user, ErrorCode::NotInRoom, &reason);
+
+        false
+    }
+
+    /// Takes `username` out of the room's members, getting rid of the room
+    /// once nobody is left in it.
+    fn forget_member(&mut self, room: &str, username: &str) {
+        if let Some(r) = self.rooms.get_mut(room) {
+            r.members.remove(username);
+
+            if r.members.is_empty() && room != DEFAULT_ROOM {
+                self.rooms.remove(room);
+            }
+        }
+    }
+
+    /// Gives everyone's queued messages a last chance to go out, waiting up
+    /// to `FLUSH_TIMEOUT` for slow users.
+    fn flush_all(&mut self) {
+        let deadline = Instant::now() + FLUSH_TIMEOUT;
+
+        loop {
+            let pending = self
+                .users
+                .values_mut()
+                .filter(|user| !user.failed)
+                .fold(false, |pending, user| {
+                    pending | !user.conn.flush_pending().unwrap_or(true)
+                });
+
+            if !pending || Instant::now() >= deadline {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn close(&mut self, mut user: User) {
+        for room in user.rooms.iter() {
+            self.forget_member(room, &user.name);
+        }
+
+        if user.dropped > 0 {
+            println!(
+                "INFO: {} messages were dropped for {}",
+                user.dropped, user.name
+            );
+            self.dropped_messages += user.dropped;
+        }
+
+        let stream = user.conn.stream_mut();
+
+        self.registry
+            .deregister(stream)
+            .unwrap_or_else(|e| eprintln!("ERROR: {:?}", e));
+        // The peer may be gone already, in which case there's nothing to shut
+        // down
+        let _ = stream.shutdown();
+    }
+
+    /// Sends `msg` to everyone except `origin`.
+    fn announce(&mut self, origin: &str, msg: Message) {
+        self.deliver(&msg, |user| user.name != origin);
+    }
+
+    /// Sends `msg` to everyone in `room`, except `origin` if there's one.
+    fn announce_room(&mut self, room: &str, origin: Option<&str>, msg: Message) {
+        self.deliver(&msg, |user| {
+            user.rooms.contains(room) && origin.is_none_or(|origin| user.name != origin)
+        });
+    }
+
+    /// Whether `msg` still fits in a frame now that the server filled it in.
+    /// What was fine coming from `username` can grow past the limit, and
+    /// then it's refused rather than failing for everyone getting it.
+    fn fits(&mut self, username: &str, msg: &Message) -> bool {
+        let size = msg.encode().map_or(usize::MAX, |m| m.len());
+        if size <= self.config.max_frame_size {
+            return true;
+        }
+
+        if let Some(user) = self.users.find_by_username_mut(username) {
+            let reason = format!(
+                "Message is too long, by {} bytes",
+                size - self.config.max_frame_size
+            );
+            reject(user, ErrorCode::MessageTooLarge, &reason);
+        }
+
+        false
+    }
+
+    /// Sends `msg` to every user `to` picks. Users we fail to reach are
+    /// dropped.
+    fn deliver<F>(&mut self, msg: &Message, to: F)
+    where
+        F: Fn(&User) -> bool,
+    {
+        let sender = &self.sender;
+
+        self.users.for_each_mut(|user| {
+            if user.failed || !to(user) {
+                return;
+            }
+
+            user.send(msg).unwrap_or_else(|e| {
+                // Same for everyone, so it's no reason to drop anybody
+                if let ServerError::FrameTooLarge(_) = e {
+                    eprintln!("ERROR: Failed broadcasting to {}: {:?}", &user.name, e);
+                    return;
+                }
+
+                user.failed = true;
+                eprintln!("ERROR: Failed broadcasting to {}: {:?}", &user.name, e);
+                sender
+                    .send(Action::Dropped(user.name.clone()))
+                    .expect("Failed to drop user");
+            });
+        });
+    }
+}
+
+fn handshake_client(
+    user: &mut User,
+    version: u32,
+    features: &[Feature],
+) -> Result<(), ServerError> {
+    let version = match negotiate_version(version) {
+        Some(v) => v,
+        None => {
+            let err = HandshakeError::IncompatibleVersion(version);
+            reject(user, ErrorCode::IncompatibleVersion, &err.to_string());
+            return Err(ServerError::FailedHandshake(err));
+        }
+    };
+
+    let features = negotiate_features(SERVER_FEATURES, features);
+    user.features = features.clone();
+    user.send(&Message::Welcome { version, features })?;
+    user.stage = Stage::Username {
+        attempts: 0,
+        checking: false,
+    };
 
     Ok(())
 }
 
-fn get_user(stream: &mut TcpStream) -> Result<String, ServerError> {
-    let username: String;
+fn list_rooms(rooms: &HashMap<String, Room>) -> Vec<RoomInfo> {
+    let mut list: Vec<RoomInfo> = rooms
+        .iter()
+        .map(|(name, room)| RoomInfo {
+            name: name.clone(),
+            members: room.members.len(),
+        })
+        .collect();
+    list.sort_by(|a, b| a.name.cmp(&b.name));
+
+    list
+}
+
+/// Names of everyone that joined, sorted.
+fn list_users(users: &HashMap<Token, User>) -> Vec<&str> {
+    let mut names: Vec<&str> = users
+        .values()
+        .filter(|u| u.stage == Stage::Joined)
+        .map(|u| u.name.as_str())
+        .collect();
+    names.sort_unstable();
+
+    names
+}
 
-    handshake_client(stream)?;
+fn check_username(name: &str, users: &HashMap<Token, User>) -> Result<(), (ErrorCode, String)> {
+    validate_username(name).map_err(|reason| (ErrorCode::InvalidUsername, reason))?;
 
-    username = read_to_string(stream)?;
+    if users.has_user(name) {
+        return Err((
+            ErrorCode::UsernameTaken,
+            format!("Username {} is already taken", name),
+        ));
+    }
 
-    Ok(username)
+    Ok(())
 }
 
-fn handshake_client(stream: &mut TcpStream) -> Result<(), ServerError> {
-    match read_to_string(stream) {
-        Ok(handshake) if handshake == common::SUPER_SECRET_CLIENT_HANDSHAKE => {
-            send_string(stream, common::SUPER_SECRET_SERVER_HANDSHAKE.to_owned())?;
+/// Whether `name` may be used. Registered names come back with their hash
+/// and the password to check against it.
+fn authenticate(
+    name: &str,
+    password: Option<String>,
+    accounts: &Accounts,
+) -> Result<Option<(String, String)>, (ErrorCode, String)> {
+    if is_guest(name) {
+        return Ok(None);
+    }
 
-            Ok(())
+    let hash = match accounts.hash_of(name) {
+        Some(hash) => hash.to_owned(),
+        None => {
+            return Err((
+                ErrorCode::AuthenticationFailed,
+                format!(
+                    "{} is not registered, join with a name starting with {} and /register it",
+                    name, GUEST_PREFIX
+                ),
+            ))
         }
-        Err(e) => Err(ServerError::from(e)),
-        _ => Err(ServerError::FailedHandshake),
+    };
+
+    match password {
+        Some(password) => Ok(Some((hash, password))),
+        None => Err((
+            ErrorCode::AuthenticationFailed,
+            format!("{} is registered, a password is needed", name),
+        )),
     }
 }
 
-fn greet_user(user: &str) {
-    println!("New user joined the party! Welcome {}!", user);
+/// Password work for the worker thread.
+enum PasswordJob {
+    Verify {
+        token: Token,
+        username: String,
+        password: String,
+        hash: String,
+    },
+    Hash {
+        from: String,
+        username: String,
+        password: String,
+    },
 }
 
-fn create_action_processor(
-    receiver: Receiver<Action>,
+/// Argon2 is slow on purpose, so passwords are hashed and checked on a
+/// thread of their own, one at a time, with the results coming back as
+/// `Action`s. The thread stops once the returned sender is dropped.
+fn spawn_password_worker(
     sender: Sender<Action>,
-    users: Arc<RwLock<Vec<User>>>,
-) -> Result<JoinHandle<()>, io::Error> {
-    let writter = thread::Builder::new()
-        .name("action_processor".into())
-        .spawn(move || {
-            writter_loop(receiver, sender, users);
-        })?;
+    waker: Arc<Waker>,
+) -> io::Result<Sender<PasswordJob>> {
+    let (jobs, receiver) = channel();
 
-    Ok(writter)
-}
-
-fn create_buttler(
-    listener: TcpListener,
-    buttler_sender: Sender<Action>,
-    buttler_running: Arc<AtomicBool>,
-) -> Result<JoinHandle<()>, io::Error> {
-    let buttler = thread::Builder::new()
-        .name("buttler".into())
+    thread::Builder::new()
+        .name("passwords".into())
         .spawn(move || {
-            listener
-                .set_nonblocking(true)
-                .expect("Cannot set non-blocking");
-
-            for stream in listener.incoming() {
-                match stream {
-                    Ok(stream) => receive_new_connection(stream, buttler_sender.clone())
-                        .unwrap_or_else(|e| {
-                            eprintln!("ERROR: {:?}", e);
-                        }),
-                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
-                        thread::sleep(Duration::from_millis(5));
-                        thread::yield_now();
-                    }
-                    Err(e) => println!("Error connecting new listener: {:?}", e),
-                }
+            for job in receiver {
+                let action = match job {
+                    PasswordJob::Verify {
+                        token,
+                        username,
+                        password,
+                        hash,
+                    } => Action::Verified {
+                        token,
+                        username,
+                        valid: accounts::verify(&hash, &password),
+                    },
+                    PasswordJob::Hash {
+                        from,
+                        username,
+                        password,
+                    } => Action::Hashed {
+                        from,
+                        username,
+                        hash: accounts::hash(&password),
+                    },
+                };
 
-                if !buttler_running.load(Ordering::SeqCst) {
-                    // Signal has been received. Exiting
-                    println!("Buttler shutting down...");
+                if sender.send(action).is_err() || waker.wake().is_err() {
                     return;
                 }
             }
         })?;
 
-    Ok(buttler)
+    Ok(jobs)
 }
 
-fn writter_loop(receiver: Receiver<Action>, sender: Sender<Action>, users: Arc<RwLock<Vec<User>>>) {
-    for action in receiver {
-        match action {
-            Action::Goodbye(name) => {
-                users.delete_user(&name).unwrap();
-                println!("Bye bye {}!", name);
-            }
-            Action::Dropped(name) => {
-                users.delete_user(&name).unwrap();
-                println!("INFO: Disconnecting dropped user: {}!", name);
-            }
-            Action::Shutdown => {
-                // TODO: Send message to clients to shutdown
-                println!("writter: Shutdown");
-                break;
-            }
-            Action::Broadcast {
-                username,
-                message: msg,
-            } => {
-                users.for_each_mut(|user| {
-                    if user.name == username {
-                        println!("{}: {}", &user.name, &msg);
-                    } else {
-                        send_string(&mut user.stream, format!("{}: {}", &username, &msg))
-                            .unwrap_or_else(|e| {
-                                eprintln!("ERROR: Failed broadcasting to {}: {:?}", &user.name, e);
-                                sender
-                                    .send(Action::Dropped(username.clone()))
-                                    .expect("Failed to drop user");
-                            });
-                    }
-                });
-            }
-            Action::NewUser { username, stream } => {
-                greet_user(&username);
-                users.add_user(User::new(username, Box::new(stream)));
-            }
-        }
-    }
+/// Tells the client what went wrong. Failing to do so is only logged, since a
+/// broken connection will be noticed by the next read anyway.
+fn reject(user: &mut User, code: ErrorCode, reason: &str) {
+    let msg = Message::Error {
+        code,
+        reason: reason.to_owned(),
+    };
+    user.send(&msg)
+        .unwrap_or_else(|e| eprintln!("ERROR: Failed to reject: {:?}", e));
 }
-fn receive_new_connection(
-    mut stream: TcpStream,
-    sender: Sender<Action>,
-) -> Result<(), ServerError> {
-    setup_stream(&stream).expect("Failed to setup connection");
-
-    let name = get_user(&mut stream)?;
 
-    let mut action = Action::NewUser {
-        username: name.clone(),
-        stream,
-    };
+fn greet_user(user: &str) {
+    println!("New user joined the party! Welcome {}!", user);
+}
 
-    loop {
-        match sender.send(action) {
-            Ok(_) => break,
-            Err(e) => {
-                println!("Failed to add new user: {}", name);
-                eprintln!("Error: {}", e);
-                action = e.0
-            }
-        }
+fn goodbye(username: &str, reason: Option<String>) -> Message {
+    Message::Goodbye {
+        username: username.to_owned(),
+        reason,
     }
-
-    Ok(())
 }
 
 trait ManageUsers {
-    // fn find_by_username(&self, name: &str) -> Option<&User>;
+    fn has_user(&self, name: &str) -> bool;
 
-    fn delete_user(&self, name: &str) -> Result<User, &str>;
+    fn find_by_username_mut(&mut self, name: &str) -> Option<&mut User>;
 
-    fn add_user(&self, user: User);
+    fn delete_user(&mut self, name: &str) -> Result<User, &'static str>;
 
-    fn for_each_mut<T>(&self, f: T)
+    fn for_each_mut<T>(&mut self, f: T)
     where
         T: FnMut(&mut User);
 }
 
-impl ManageUsers for Arc<RwLock<Vec<User>>> {
-    // fn find_by_username(&self, name: &str) -> Option<&User> {
-    //     loop {
-    //         if let Ok(users) = self.try_read() {
-    //             return users.iter().find(|u| &u.name == name);
-    //         }
-    //     }
-    // }
+impl ManageUsers for HashMap<Token, User> {
+    fn has_user(&self, name: &str) -> bool {
+        self.values()
+            .any(|u| u.stage == Stage::Joined && u.name == name)
+    }
 
-    fn delete_user(&self, name: &str) -> Result<User, &str> {
-        loop {
-            if let Ok(mut users) = self.try_write() {
-                if let Some((i, _)) = users.iter().enumerate().find(|(_, u)| &u.name == name) {
-                    return Ok(users.remove(i));
-                } else {
-                    return Err(&"Could not find user");
-                }
-            }
-        }
+    fn find_by_username_mut(&mut self, name: &str) -> Option<&mut User> {
+        self.values_mut()
+            .find(|u| u.stage == Stage::Joined && u.name == name)
     }
 
-    fn add_user(&self, user: User) {
-        loop {
-            if let Ok(mut users) = self.try_write() {
-                users.push(user);
-                return;
-            }
+    fn delete_user(&mut self, name: &str) -> Result<User, &'static str> {
+        let token = self
+            .iter()
+            .find(|(_, u)| u.stage == Stage::Joined && u.name == name)
+            .map(|(token, _)| *token);
+
+        match token {
+            Some(token) => Ok(self.remove(&token).expect("User to be connected")),
+            None => Err("Could not find user"),
         }
     }
 
-    fn for_each_mut<T>(&self, mut f: T)
+    fn for_each_mut<T>(&mut self, mut f: T)
     where
         T: FnMut(&mut User),
     {
-        loop {
-            if let Ok(mut users) = self.try_write() {
-                for user in users.iter_mut() {
-                    f(user);
-                }
-                break;
+        for user in self.values_mut() {
+            if user.stage == Stage::Joined {
+                f(user);
             }
         }
     }
@@ -0,0 +1,245 @@
+use std::error::Error;
+use std::io::{self, ErrorKind, Read, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mio::event::Source;
+use mio::{Interest, Registry, Token};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConnection, DigitallySignedStruct, RootCertStore, ServerConnection, SignatureScheme,
+    StreamOwned,
+};
+
+use crate::transport::Transport;
+
+/// How long the server gets to finish the handshake when connecting to it.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A transport wrapped in TLS. Reads and writes are encrypted, anything else
+/// is done on the transport underneath.
+#[derive(Debug)]
+pub enum Tls<T: Transport> {
+    Server(Box<StreamOwned<ServerConnection, T>>),
+    Client(Box<StreamOwned<ClientConnection, T>>),
+}
+
+/// Wraps a transport the server accepted. The handshake happens along the
+/// way, as the client talks to us.
+pub fn accept<T: Transport>(config: Arc<rustls::ServerConfig>, inner: T) -> io::Result<Tls<T>> {
+    let conn = ServerConnection::new(config).map_err(io::Error::other)?;
+
+    Ok(Tls::Server(Box::new(StreamOwned::new(conn, inner))))
+}
+
+/// Wraps a blocking transport connected to `name`, handshaking right away.
+pub fn connect<T: Transport>(
+    config: Arc<rustls::ClientConfig>,
+    name: ServerName<'static>,
+    mut inner: T,
+) -> io::Result<Tls<T>> {
+    let mut conn = ClientConnection::new(config, name).map_err(io::Error::other)?;
+
+    inner.set_timeouts(Some(CONNECT_TIMEOUT), Some(CONNECT_TIMEOUT))?;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut inner)?;
+    }
+
+    Ok(Tls::Client(Box::new(StreamOwned::new(conn, inner))))
+}
+
+impl<T: Transport> Tls<T> {
+    fn inner(&self) -> &T {
+        match self {
+            Tls::Server(tls) => tls.get_ref(),
+            Tls::Client(tls) => tls.get_ref(),
+        }
+    }
+
+    fn inner_mut(&mut self) -> &mut T {
+        match self {
+            Tls::Server(tls) => tls.get_mut(),
+            Tls::Client(tls) => tls.get_mut(),
+        }
+    }
+}
+
+impl<T: Transport> Transport for Tls<T> {
+    fn shutdown(&mut self) -> io::Result<()> {
+        // Lets the peer know we're hanging up on purpose, if it's still there
+        match self {
+            Tls::Server(tls) => tls.conn.send_close_notify(),
+            Tls::Client(tls) => tls.conn.send_close_notify(),
+        }
+        let _ = self.flush();
+
+        self.inner_mut().shutdown()
+    }
+
+    fn set_timeouts(&self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+        self.inner().set_timeouts(read, write)
+    }
+}
+
+impl<T: Transport> Read for Tls<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = match self {
+            Tls::Server(tls) => tls.read(buf),
+            Tls::Client(tls) => tls.read(buf),
+        };
+
+        match result {
+            // The peer hung up without saying goodbye over TLS. Frames can't
+            // be cut short without us noticing, so it's like any other hang up
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+            result => result,
+        }
+    }
+}
+
+impl<T: Transport> Write for Tls<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Tls::Server(tls) => tls.write(buf),
+            Tls::Client(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Tls::Server(tls) => tls.flush(),
+            Tls::Client(tls) => tls.flush(),
+        }
+    }
+}
+
+impl<T: Transport + Source> Source for Tls<T> {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.inner_mut().register(registry, token, interests)
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        self.inner_mut().reregister(registry, token, interests)
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        self.inner_mut().deregister(registry)
+    }
+}
+
+/// TLS setup for the server, out of a PEM certificate chain and its private
+/// key.
+pub fn server_config(cert: &Path, key: &Path) -> Result<Arc<rustls::ServerConfig>, Box<dyn Error>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .map_err(|e| format!("Can't read certificates from {}: {}", cert.display(), e))?
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = PrivateKeyDer::from_pem_file(key)
+        .map_err(|e| format!("Can't read a private key from {}: {}", key.display(), e))?;
+
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+/// TLS setup for the client. The server's certificate has to be signed by
+/// one of the certificates in the `ca` PEM file, unless `insecure`, in which
+/// case anything goes.
+pub fn client_config(
+    ca: Option<&Path>,
+    insecure: bool,
+) -> Result<Arc<rustls::ClientConfig>, Box<dyn Error>> {
+    let builder = rustls::ClientConfig::builder();
+
+    let config = if insecure {
+        let provider = CryptoProvider::get_default()
+            .cloned()
+            .ok_or("No crypto provider to check signatures with")?;
+
+        builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(NoVerification(provider)))
+            .with_no_client_auth()
+    } else {
+        let mut roots = RootCertStore::empty();
+
+        if let Some(ca) = ca {
+            for cert in CertificateDer::pem_file_iter(ca)
+                .map_err(|e| format!("Can't read certificates from {}: {}", ca.display(), e))?
+            {
+                roots.add(cert?)?;
+            }
+        }
+
+        builder.with_root_certificates(roots).with_no_client_auth()
+    };
+
+    Ok(Arc::new(config))
+}
+
+/// Takes the server's certificate at face value, for `--insecure`. The
+/// handshake is still checked, so it's encrypted, just not to anyone in
+/// particular.
+#[derive(Debug)]
+struct NoVerification(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerification {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
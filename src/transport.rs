@@ -0,0 +1,236 @@
+use std::fmt::{self, Debug, Display, Formatter};
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+
+use mio::event::Source;
+use mio::net::TcpListener;
+#[cfg(unix)]
+use mio::net::UnixListener;
+use mio::{Interest, Registry, Token};
+
+use crate::tls;
+
+/// A byte stream connections can run over, like a TCP or Unix socket, either
+/// of them wrapped in TLS, or one end of an in-memory `pair`.
+pub trait Transport: Read + Write + Send + Sync + Debug {
+    /// Hangs up both ways. Fails if the peer is gone already.
+    fn shutdown(&mut self) -> io::Result<()>;
+
+    /// How long reads and writes may block. Nonblocking transports, like the
+    /// ones the server polls, have no use for them.
+    fn set_timeouts(&self, _read: Option<Duration>, _write: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A transport the server's event loop can wait on.
+pub trait Evented: Transport + Source {}
+
+impl<T: Transport + Source> Evented for T {}
+
+impl Transport for TcpStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_timeouts(&self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(read)?;
+        self.set_write_timeout(write)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for UnixStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        UnixStream::shutdown(self, Shutdown::Both)
+    }
+
+    fn set_timeouts(&self, read: Option<Duration>, write: Option<Duration>) -> io::Result<()> {
+        self.set_read_timeout(read)?;
+        self.set_write_timeout(write)
+    }
+}
+
+impl Transport for mio::net::TcpStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        mio::net::TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl Transport for mio::net::UnixStream {
+    fn shutdown(&mut self) -> io::Result<()> {
+        mio::net::UnixStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+/// An in-memory connection, without a socket file or port. The first end is
+/// nonblocking, for the server to poll, the second one is for a client.
+#[cfg(unix)]
+pub fn pair() -> io::Result<(mio::net::UnixStream, UnixStream)> {
+    let (server, client) = UnixStream::pair()?;
+    server.set_nonblocking(true)?;
+
+    Ok((mio::net::UnixStream::from_std(server), client))
+}
+
+/// Same as the Unix `pair`, over loopback TCP where there are no Unix
+/// sockets to pair up.
+#[cfg(not(unix))]
+pub fn pair() -> io::Result<(mio::net::TcpStream, TcpStream)> {
+    let listener = std::net::TcpListener::bind(("127.0.0.1", 0))?;
+    let client = TcpStream::connect(listener.local_addr()?)?;
+    let ours = client.local_addr()?;
+
+    loop {
+        let (server, peer) = listener.accept()?;
+        // Anyone on the host could have come in first
+        if peer == ours {
+            server.set_nonblocking(true)?;
+            return Ok((mio::net::TcpStream::from_std(server), client));
+        }
+    }
+}
+
+/// Where a server listens, or a client connects to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    /// Path of a Unix socket. Who may connect is up to its permissions.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Display for Endpoint {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Tcp(addr) => write!(f, "{}", addr),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+/// A socket the server takes connections from.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, PathBuf),
+}
+
+impl Listener {
+    /// Starts listening on `endpoint`. A socket file left behind by a server
+    /// that's gone is replaced, one that's still in use isn't, and neither is
+    /// anything that isn't a socket.
+    pub fn bind(endpoint: &Endpoint) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(*addr)?)),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => {
+                match fs::symlink_metadata(path) {
+                    Ok(meta) if !meta.file_type().is_socket() => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AlreadyExists,
+                            format!("{} exists and isn't a socket", path.display()),
+                        ));
+                    }
+                    Ok(_) if UnixStream::connect(path).is_ok() => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::AddrInUse,
+                            format!("{} is in use", path.display()),
+                        ));
+                    }
+                    Ok(_) => fs::remove_file(path)?,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e),
+                }
+
+                Ok(Listener::Unix(UnixListener::bind(path)?, path.clone()))
+            }
+        }
+    }
+
+    /// Where it's listening, with the port picked if it was bound to 0.
+    pub fn endpoint(&self) -> io::Result<Endpoint> {
+        match self {
+            Listener::Tcp(listener) => Ok(Endpoint::Tcp(listener.local_addr()?)),
+            #[cfg(unix)]
+            Listener::Unix(_, path) => Ok(Endpoint::Unix(path.clone())),
+        }
+    }
+
+    /// Takes the next connection waiting, wrapped in TLS when `tls` is set.
+    /// Only TCP ones are, connections over Unix sockets never leave the host.
+    pub fn accept(&self, tls: Option<&Arc<rustls::ServerConfig>>) -> io::Result<Box<dyn Evented>> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+
+                match tls {
+                    Some(tls) => Ok(Box::new(tls::accept(tls.clone(), stream)?)),
+                    None => Ok(Box::new(stream)),
+                }
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => Ok(Box::new(listener.accept()?.0)),
+        }
+    }
+}
+
+impl Source for Listener {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.register(registry, token, interests),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.register(registry, token, interests),
+        }
+    }
+
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.reregister(registry, token, interests),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.reregister(registry, token, interests),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        match self {
+            Listener::Tcp(listener) => listener.deregister(registry),
+            #[cfg(unix)]
+            Listener::Unix(listener, _) => listener.deregister(registry),
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for Listener {
+    fn drop(&mut self) {
+        // Nobody's going to answer there anymore
+        if let Listener::Unix(_, path) = self {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
@@ -0,0 +1,350 @@
+use std::io;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, List, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::client::{describe, handle_line, Session};
+use crate::commands::{Outcome, Registry};
+use crate::common::{Connection, Message};
+
+/// How long to wait for a key before looking at what came in.
+const TICK: Duration = Duration::from_millis(50);
+
+/// Lines kept for scrolling back, older ones are dropped.
+const MAX_LINES: usize = 1000;
+
+const SIDEBAR_WIDTH: u16 = 20;
+
+/// Everything the TUI shows that doesn't come from the keyboard, sent by
+/// the session instead of printing.
+#[derive(Debug)]
+pub enum Feed {
+    Message(Message),
+    /// A line of our own.
+    Notice(String),
+    /// Something that went wrong on our side.
+    Error(String),
+    Clear,
+    /// Time to go, with this exit code.
+    Exit(i32),
+}
+
+/// What's on screen, and what's being typed.
+struct App {
+    lines: Vec<(Style, String)>,
+    /// How many rows we're scrolled up from the bottom.
+    scroll: usize,
+    /// Rows the messages had last time, for paging.
+    page: usize,
+    input: Vec<char>,
+    cursor: usize,
+    history: Vec<String>,
+    /// Which history line is shown, while going through them.
+    browsing: Option<usize>,
+    /// What was typed before going through the history.
+    draft: Vec<char>,
+    /// Printed once the terminal is back to normal, as it would be gone
+    /// with the screen otherwise.
+    last: Option<String>,
+}
+
+/// Runs the chat full screen until we quit: messages on the left, who's
+/// online on the right, and a status bar above the line being typed.
+/// Takes over from `chat`, everything the session shows comes in through
+/// `feed`.
+pub fn run(
+    stream: &mut Arc<RwLock<Connection>>,
+    session: &Session,
+    running: &AtomicBool,
+    feed: Receiver<Feed>,
+    title: &str,
+) {
+    let mut terminal = match ratatui::try_init() {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            eprintln!("ERROR: Could not setup the terminal: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut app = App {
+        lines: vec![],
+        scroll: 0,
+        page: 0,
+        input: vec![],
+        cursor: 0,
+        history: vec![],
+        browsing: None,
+        draft: vec![],
+        last: None,
+    };
+    let result = run_app(
+        &mut terminal,
+        &mut app,
+        stream,
+        session,
+        running,
+        &feed,
+        title,
+    );
+
+    ratatui::restore();
+    if let Some(last) = app.last {
+        eprintln!("{}", last);
+    }
+
+    match result {
+        Ok(Some(code)) => process::exit(code),
+        Ok(None) => (),
+        Err(e) => eprintln!("ERROR: Terminal failed: {}", e),
+    }
+}
+
+/// The loop behind `run`. Returns the code to exit with if the session
+/// asked for it.
+#[allow(clippy::too_many_arguments)]
+fn run_app(
+    terminal: &mut DefaultTerminal,
+    app: &mut App,
+    stream: &mut Arc<RwLock<Connection>>,
+    session: &Session,
+    running: &AtomicBool,
+    feed: &Receiver<Feed>,
+    title: &str,
+) -> io::Result<Option<i32>> {
+    let commands = Registry::new();
+
+    while running.load(Ordering::SeqCst) {
+        if let Some(code) = app.take_all(feed) {
+            return Ok(Some(code));
+        }
+
+        terminal.draw(|frame| app.draw(frame, session, title))?;
+
+        if !event::poll(TICK)? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if let Some(line) = app.key(key) {
+                if let Outcome::Quit = handle_line(stream, session, &commands, &line) {
+                    // Whatever quitting had to say
+                    app.take_all(feed);
+                    break;
+                }
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+impl App {
+    /// Takes everything fed so far, stopping at an exit.
+    fn take_all(&mut self, feed: &Receiver<Feed>) -> Option<i32> {
+        for item in feed.try_iter() {
+            match item {
+                Feed::Exit(code) => return Some(code),
+                item => self.take(item),
+            }
+        }
+
+        None
+    }
+
+    fn take(&mut self, item: Feed) {
+        match item {
+            Feed::Message(msg) => {
+                let style = match msg {
+                    Message::Error { .. } => Style::new().fg(Color::Red),
+                    Message::Direct { .. } => Style::new().fg(Color::Magenta),
+                    _ => Style::new(),
+                };
+                for line in describe(&msg) {
+                    self.push(style, line);
+                }
+            }
+            Feed::Notice(line) => {
+                self.last = Some(line.clone());
+                self.push(Style::new(), line);
+            }
+            Feed::Error(reason) => {
+                let line = format!("ERROR: {}", reason);
+                self.last = Some(line.clone());
+                self.push(Style::new().fg(Color::Red), line);
+            }
+            Feed::Clear => {
+                self.lines.clear();
+                self.scroll = 0;
+            }
+            Feed::Exit(_) => (),
+        }
+    }
+
+    fn push(&mut self, style: Style, line: String) {
+        let style = match line.starts_with("***") {
+            true => style.add_modifier(Modifier::DIM),
+            false => style,
+        };
+
+        self.lines.push((style, line));
+        if self.lines.len() > MAX_LINES {
+            self.lines.remove(0);
+        }
+    }
+
+    /// Handles a key, returning the line typed once Enter is hit.
+    fn key(&mut self, key: KeyEvent) -> Option<String> {
+        let control = key.modifiers.contains(KeyModifiers::CONTROL);
+
+        match key.code {
+            KeyCode::Char('c') if control => return Some("/quit".into()),
+            KeyCode::Char('d') if control && self.input.is_empty() => return Some("/quit".into()),
+            KeyCode::Char('u') if control => {
+                self.input.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            KeyCode::Char('a') if control => self.cursor = 0,
+            KeyCode::Char('e') if control => self.cursor = self.input.len(),
+            KeyCode::Char(_) if control => (),
+            KeyCode::Char(c) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::Up => self.browse(-1),
+            KeyCode::Down => self.browse(1),
+            KeyCode::PageUp => self.scroll += self.page.max(1),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page.max(1)),
+            KeyCode::Enter => {
+                let line: String = self.input.drain(..).collect();
+                self.cursor = 0;
+                self.browsing = None;
+                // Whatever we say, we want to see it land
+                self.scroll = 0;
+
+                if !line.trim().is_empty() && self.history.last() != Some(&line) {
+                    self.history.push(line.clone());
+                }
+                return Some(line);
+            }
+            _ => (),
+        }
+
+        None
+    }
+
+    /// Moves `by` lines through the history, past the newest one being
+    /// whatever was typed before.
+    fn browse(&mut self, by: isize) {
+        let current = self.browsing.unwrap_or(self.history.len());
+        let next = current.saturating_add_signed(by).min(self.history.len());
+        if next == current {
+            return;
+        }
+
+        if self.browsing.is_none() {
+            self.draft = self.input.clone();
+        }
+        self.input = match self.history.get(next) {
+            Some(line) => line.chars().collect(),
+            None => self.draft.clone(),
+        };
+        self.browsing = (next < self.history.len()).then_some(next);
+        self.cursor = self.input.len();
+    }
+
+    fn draw(&mut self, frame: &mut Frame, session: &Session, title: &str) {
+        let [main, status, input] = Layout::vertical([
+            Constraint::Min(1),
+            Constraint::Length(1),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [messages, sidebar] =
+            Layout::horizontal([Constraint::Min(1), Constraint::Length(SIDEBAR_WIDTH)]).areas(main);
+
+        self.draw_messages(frame, messages);
+
+        let users = session.users.read().unwrap().clone();
+        let online = format!(" Online ({}) ", users.len());
+        let users = List::new(users).block(Block::new().borders(Borders::LEFT).title(online));
+        frame.render_widget(users, sidebar);
+
+        let username = session.username.read().unwrap().clone();
+        let room = session.room.read().unwrap().clone();
+        let (state, color) = match session.online.load(Ordering::SeqCst) {
+            true => ("online", Color::Blue),
+            false => ("reconnecting...", Color::Red),
+        };
+        let mut line = format!(" {} @ {} | {} | {}", username, title, room, state);
+        if self.scroll > 0 {
+            line.push_str(&format!(" | scrolled up {} lines", self.scroll));
+        }
+        let bar = Paragraph::new(line).style(Style::new().bg(color).fg(Color::White));
+        frame.render_widget(bar, status);
+
+        self.draw_input(frame, input, &format!("{} {}: ", room, username));
+    }
+
+    /// Shows as much of the end of the conversation as fits, long lines
+    /// wrapped, `scroll` rows up from the bottom.
+    fn draw_messages(&mut self, frame: &mut Frame, area: Rect) {
+        let width = usize::from(area.width).max(1);
+        let height = usize::from(area.height);
+        let rows: Vec<Line> = self
+            .lines
+            .iter()
+            .flat_map(|&(style, ref line)| {
+                let chars: Vec<char> = line.chars().collect();
+                let chunks: Vec<String> = match chars.is_empty() {
+                    true => vec![String::new()],
+                    false => chars.chunks(width).map(|c| c.iter().collect()).collect(),
+                };
+                chunks.into_iter().map(move |c| Line::styled(c, style))
+            })
+            .collect();
+
+        self.page = height;
+        self.scroll = self.scroll.min(rows.len().saturating_sub(height));
+        let end = rows.len() - self.scroll;
+        let start = end.saturating_sub(height);
+
+        frame.render_widget(Paragraph::new(rows[start..end].to_vec()), area);
+    }
+
+    /// Shows the line being typed after `prompt`, moved along so the cursor
+    /// stays in view.
+    fn draw_input(&self, frame: &mut Frame, area: Rect, prompt: &str) {
+        let prompt_width = prompt.chars().count();
+        let room = usize::from(area.width).saturating_sub(prompt_width + 1);
+        let offset = self.cursor.saturating_sub(room);
+        let shown: String = self.input[offset..].iter().take(room + 1).collect();
+
+        frame.render_widget(Paragraph::new(format!("{}{}", prompt, shown)), area);
+
+        let x = (prompt_width + self.cursor - offset).min(usize::from(area.width));
+        frame.set_cursor_position((area.x + x as u16, area.y));
+    }
+}
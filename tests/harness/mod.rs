@@ -0,0 +1,184 @@
+//! Runs a server inside the test process, with scripted clients to poke it.
+
+// Not every test crate needs all of it
+#![allow(dead_code)]
+
+use std::fmt::Debug;
+use std::net::TcpStream;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use chat_rs::common::{Connection, Message, DEFAULT_MAX_FRAME_SIZE};
+use chat_rs::{Client, ClientConfig, Credentials, Endpoint, Handle, ServerBuilder, ServerError};
+
+/// How long anything is waited for before the test fails.
+pub const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server running on its own thread, on a port nobody else uses.
+pub struct TestServer {
+    endpoint: Endpoint,
+    handle: Handle,
+    thread: Option<JoinHandle<Result<(), ServerError>>>,
+}
+
+impl TestServer {
+    pub fn start() -> Self {
+        TestServer::with(ServerBuilder::new())
+    }
+
+    /// Starts whatever `builder` puts together, also listening on a free
+    /// port.
+    pub fn with(builder: ServerBuilder) -> Self {
+        let server = builder
+            .bind(Endpoint::Tcp("127.0.0.1:0".parse().unwrap()))
+            .build()
+            .expect("Server to start");
+        let endpoint = server.endpoints().pop().expect("Bound endpoint");
+        let handle = server.handle();
+
+        let thread = thread::spawn(move || server.run());
+
+        TestServer {
+            endpoint,
+            handle,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn endpoint(&self) -> &Endpoint {
+        &self.endpoint
+    }
+
+    /// Connects over TCP without saying anything, for talking to the server
+    /// frame by frame.
+    pub fn connect_raw(&self) -> Connection<TcpStream> {
+        let addr = match &self.endpoint {
+            Endpoint::Tcp(addr) => *addr,
+            #[cfg(unix)]
+            Endpoint::Unix(_) => unreachable!("Test servers listen on TCP"),
+        };
+        let stream = TcpStream::connect(addr).expect("Raw connection");
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+
+        Connection::new(stream, DEFAULT_MAX_FRAME_SIZE)
+    }
+
+    /// Joins as guest `username` over TCP.
+    pub fn join(&self, username: &str) -> Client {
+        Client::connect(&self.endpoint, guest(username), &ClientConfig::default())
+            .unwrap_or_else(|e| panic!("{} could not join: {}", username, e))
+    }
+
+    /// Joins as guest `username` without going through a socket.
+    pub fn join_in_memory(&self, username: &str) -> Client {
+        let stream = self.handle.connect().expect("In-memory connection");
+
+        Client::over(stream, guest(username), &ClientConfig::default())
+            .unwrap_or_else(|e| panic!("{} could not join: {}", username, e))
+    }
+
+    /// Joins everyone in `usernames`, returning once they all know about
+    /// each other, so nobody misses what's said next.
+    pub fn crowd(&self, usernames: &[&str]) -> Vec<Client> {
+        let mut clients: Vec<Client> = vec![];
+
+        for username in usernames {
+            let newcomer = self.join(username);
+
+            for client in clients.iter_mut() {
+                expect(
+                    client,
+                    "join",
+                    |m| matches!(m, Message::Join { username: u, .. } if u == username),
+                );
+            }
+
+            clients.push(newcomer);
+        }
+
+        clients
+    }
+
+    /// Shuts the server down with `reason` and waits for it to finish.
+    pub fn stop(mut self, reason: &str) {
+        self.handle.shutdown(reason).expect("Shutdown");
+
+        let thread = self.thread.take().expect("Server thread");
+        thread
+            .join()
+            .expect("Server thread panicked")
+            .expect("Server to stop cleanly");
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        // A test that failed halfway still shouldn't leave the server behind
+        if let Some(thread) = self.thread.take() {
+            let _ = self.handle.shutdown("Test is over");
+            let _ = thread.join();
+        }
+    }
+}
+
+pub fn guest(username: &str) -> Credentials {
+    Credentials {
+        username: username.into(),
+        password: None,
+    }
+}
+
+/// Waits for the first message `wanted` picks, skipping the rest. Fails the
+/// test after `TIMEOUT`, listing what came in meanwhile.
+pub fn expect<F>(client: &mut Client, what: &str, wanted: F) -> Message
+where
+    F: Fn(&Message) -> bool,
+{
+    let deadline = Instant::now() + TIMEOUT;
+    let mut skipped = vec![];
+
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+
+        match client.recv(Some(left)) {
+            Ok(Some(msg)) if wanted(&msg) => return msg,
+            Ok(Some(msg)) => skipped.push(msg),
+            Ok(None) => fail(client, &format!("Timed out waiting for {}", what), &skipped),
+            Err(e) => fail(
+                client,
+                &format!("{} while waiting for {}", e, what),
+                &skipped,
+            ),
+        }
+    }
+}
+
+/// Checks nothing `unwanted` picks comes in for a little while.
+pub fn expect_none<F>(client: &mut Client, what: &str, unwanted: F)
+where
+    F: Fn(&Message) -> bool,
+{
+    let deadline = Instant::now() + Duration::from_millis(200);
+
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+
+        match client.recv(Some(left)) {
+            Ok(Some(msg)) if unwanted(&msg) => {
+                panic!(
+                    "{} got {} it shouldn't have: {:?}",
+                    client.username(),
+                    what,
+                    msg
+                )
+            }
+            Ok(Some(_)) => continue,
+            Ok(None) => return,
+            Err(e) => panic!("{}: {} while waiting", client.username(), e),
+        }
+    }
+}
+
+fn fail<T: Debug>(client: &Client, reason: &str, skipped: &[T]) -> ! {
+    panic!("{}: {}, got {:#?}", client.username(), reason, skipped)
+}
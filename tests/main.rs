@@ -1,3 +1,19 @@
+mod harness;
+
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use chat_rs::common::{
+    encode_frame, read_message, send_message, ErrorCode, Feature, Message, OverflowPolicy,
+    DEFAULT_ROOM, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use chat_rs::{Client, ClientConfig, Credentials, ServerBuilder, ServerError};
+
+use harness::{expect, expect_none, guest, TestServer, TIMEOUT};
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -5,3 +21,615 @@ mod tests {
         assert_eq!(2 + 2, 4);
     }
 }
+
+fn is_chat(text: &str) -> impl Fn(&Message) -> bool + '_ {
+    move |m| matches!(m, Message::Chat { text: t, .. } if t == text)
+}
+
+/// Chats sent to a user that isn't reading. Well past what the sockets in
+/// between can hold, so the server's queue has to overflow.
+const FLOOD: usize = 400;
+
+fn flood_line(i: usize) -> String {
+    format!("{} {}", i, "x".repeat(32 * 1024))
+}
+
+#[test]
+fn handshake_agrees_on_features() {
+    let server = TestServer::start();
+
+    let alice = server.join("guest-alice");
+    assert_eq!(alice.username(), "guest-alice");
+    assert_eq!(
+        alice.features(),
+        &[Feature::History, Feature::Rooms, Feature::Users]
+    );
+
+    server.stop("Done");
+}
+
+#[test]
+fn unsupported_versions_are_refused() {
+    let server = TestServer::start();
+    let mut conn = server.connect_raw();
+
+    let hello = Message::Hello {
+        version: MIN_PROTOCOL_VERSION - 1,
+        features: vec![],
+    };
+    send_message(&mut conn, &hello).unwrap();
+    assert!(matches!(
+        read_message(&mut conn).unwrap(),
+        Message::Error {
+            code: ErrorCode::IncompatibleVersion,
+            ..
+        }
+    ));
+    // And hung up on
+    assert!(read_message(&mut conn).is_err());
+
+    server.stop("Done");
+}
+
+#[test]
+fn taken_usernames_are_refused() {
+    let server = TestServer::start();
+    let _alice = server.join("guest-alice");
+
+    let result = Client::connect(
+        server.endpoint(),
+        guest("guest-alice"),
+        &ClientConfig::default(),
+    );
+    match result {
+        Err(ServerError::FailedHandshake(e)) => assert!(e.to_string().contains("taken")),
+        Err(e) => panic!("Refused for the wrong reason: {}", e),
+        Ok(_) => panic!("Joined with a taken name"),
+    }
+
+    server.stop("Done");
+}
+
+#[test]
+fn chat_reaches_everyone_else() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-alice", "guest-bob", "guest-carol"]);
+
+    clients[0].chat(DEFAULT_ROOM, "hi all").unwrap();
+
+    for client in clients[1..].iter_mut() {
+        let msg = expect(client, "chat", is_chat("hi all"));
+        assert_eq!(
+            msg,
+            Message::Chat {
+                from: "guest-alice".into(),
+                room: Some(DEFAULT_ROOM.into()),
+                text: "hi all".into(),
+            }
+        );
+    }
+    expect_none(&mut clients[0], "an echo", is_chat("hi all"));
+
+    server.stop("Done");
+}
+
+#[test]
+fn direct_messages_reach_only_their_target() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-alice", "guest-bob", "guest-carol"]);
+
+    clients[0]
+        .send(&Message::Direct {
+            from: String::new(),
+            to: "guest-bob".into(),
+            text: "psst".into(),
+        })
+        .unwrap();
+
+    let msg = expect(&mut clients[1], "direct", |m| {
+        matches!(m, Message::Direct { .. })
+    });
+    assert!(
+        matches!(msg, Message::Direct { from, text, .. } if from == "guest-alice" && text == "psst")
+    );
+    expect_none(&mut clients[2], "a direct", |m| {
+        matches!(m, Message::Direct { .. })
+    });
+
+    // Nobody by that name
+    clients[0]
+        .send(&Message::Direct {
+            from: String::new(),
+            to: "guest-dave".into(),
+            text: "hello?".into(),
+        })
+        .unwrap();
+    expect(&mut clients[0], "error", |m| {
+        matches!(m, Message::Error { .. })
+    });
+
+    server.stop("Done");
+}
+
+#[test]
+fn leaving_is_announced() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-alice", "guest-bob", "guest-carol"]);
+
+    let carol = clients.pop().unwrap();
+    carol.close(Some("lunch")).unwrap();
+    let msg = expect(&mut clients[0], "goodbye", |m| {
+        matches!(m, Message::Goodbye { .. })
+    });
+    assert_eq!(
+        msg,
+        Message::Goodbye {
+            username: "guest-carol".into(),
+            reason: Some("lunch".into()),
+        }
+    );
+
+    // Hanging up without a word counts as leaving too
+    drop(clients.pop());
+    let msg = expect(&mut clients[0], "goodbye", |m| {
+        matches!(m, Message::Goodbye { .. })
+    });
+    assert!(matches!(msg, Message::Goodbye { username, .. } if username == "guest-bob"));
+
+    // And frees the name
+    server.join("guest-bob");
+
+    server.stop("Done");
+}
+
+#[test]
+fn in_memory_clients_talk_to_everyone() {
+    let server = TestServer::start();
+    let mut alice = server.join("guest-alice");
+    let mut bob = server.join_in_memory("guest-bob");
+
+    expect(
+        &mut alice,
+        "join",
+        |m| matches!(m, Message::Join { username, .. } if username == "guest-bob"),
+    );
+    bob.chat(DEFAULT_ROOM, "from memory").unwrap();
+    expect(&mut alice, "chat", is_chat("from memory"));
+
+    alice.chat(DEFAULT_ROOM, "from tcp").unwrap();
+    expect(&mut bob, "chat", is_chat("from tcp"));
+
+    server.stop("Done");
+}
+
+#[test]
+fn shutdown_reaches_everyone() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-alice", "guest-bob"]);
+
+    server.stop("Maintenance");
+
+    for client in clients.iter_mut() {
+        let msg = expect(client, "shutdown", |m| {
+            matches!(m, Message::Shutdown { .. })
+        });
+        assert_eq!(
+            msg,
+            Message::Shutdown {
+                reason: "Maintenance".into(),
+                grace: None,
+            }
+        );
+
+        // Nothing else comes after, the server hung up
+        assert!(matches!(
+            client.recv(Some(harness::TIMEOUT)),
+            Err(ServerError::UserShutdown)
+        ));
+    }
+}
+
+#[test]
+fn refusals_come_with_a_code() {
+    let server = TestServer::start();
+    let mut alice = server.join("guest-alice");
+
+    alice
+        .send(&Message::Command {
+            name: "nick".into(),
+            args: vec!["bob".into()],
+            room: None,
+        })
+        .unwrap();
+    let msg = expect(&mut alice, "error", |m| matches!(m, Message::Error { .. }));
+    assert!(matches!(
+        msg,
+        Message::Error {
+            code: ErrorCode::CommandFailed,
+            ..
+        }
+    ));
+
+    server.stop("Done");
+}
+
+#[test]
+fn users_online_are_listed() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-carol", "guest-alice", "guest-bob"]);
+
+    clients[0].send(&Message::ListUsers).unwrap();
+    let msg = expect(&mut clients[0], "users", |m| {
+        matches!(m, Message::Users { .. })
+    });
+    assert_eq!(
+        msg,
+        Message::Users {
+            users: vec![
+                "guest-alice".into(),
+                "guest-bob".into(),
+                "guest-carol".into()
+            ],
+        }
+    );
+
+    server.stop("Done");
+}
+
+#[test]
+fn chat_too_large_to_pass_on_is_refused() {
+    // With a queue of one, anyone it had been queued for would be dropped
+    let builder = ServerBuilder::new()
+        .max_frame_size(1024)
+        .max_queue(1, OverflowPolicy::Disconnect);
+    let server = TestServer::with(builder);
+    let mut clients = server.crowd(&["guest-alice", "guest-bob"]);
+
+    // Fits as sent, but not once the server fills in who and where
+    let sent = Message::Chat {
+        from: String::new(),
+        room: None,
+        text: "x".repeat(980),
+    };
+    assert!(sent.encode().unwrap().len() <= 1024);
+    clients[0].send(&sent).unwrap();
+
+    let msg = expect(&mut clients[0], "error", |m| {
+        matches!(m, Message::Error { .. })
+    });
+    assert!(matches!(
+        msg,
+        Message::Error {
+            code: ErrorCode::MessageTooLarge,
+            ..
+        }
+    ));
+
+    // Nobody got dropped over it
+    clients[0].chat(DEFAULT_ROOM, "still here?").unwrap();
+    expect(&mut clients[1], "chat", is_chat("still here?"));
+
+    server.stop("Done");
+}
+
+#[test]
+fn registered_names_need_their_password() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-alice", "guest-bob"]);
+
+    clients[0]
+        .send(&Message::Command {
+            name: "register".into(),
+            args: vec!["alice".into(), "hunter22".into()],
+            room: None,
+        })
+        .unwrap();
+    expect(
+        &mut clients[0],
+        "registration",
+        |m| matches!(m, Message::System { text } if text == "alice is now registered"),
+    );
+    let alice = clients.remove(0);
+    alice.close(None).unwrap();
+    expect(&mut clients[0], "goodbye", |m| {
+        matches!(m, Message::Goodbye { .. })
+    });
+
+    let login = |password: &str| {
+        let credentials = Credentials {
+            username: "alice".into(),
+            password: Some(password.into()),
+        };
+        Client::connect(server.endpoint(), credentials, &ClientConfig::default())
+    };
+    match login("wrong") {
+        Err(ServerError::FailedHandshake(e)) => assert!(e.to_string().contains("Wrong password")),
+        Err(e) => panic!("Refused for the wrong reason: {}", e),
+        Ok(_) => panic!("Joined with the wrong password"),
+    }
+    let mut alice = login("hunter22").expect("alice to join");
+
+    clients[0].chat(DEFAULT_ROOM, "welcome back").unwrap();
+    expect(&mut alice, "chat", is_chat("welcome back"));
+
+    server.stop("Done");
+}
+
+#[test]
+fn history_too_large_for_a_frame_is_cut_short() {
+    let server = TestServer::with(ServerBuilder::new().max_frame_size(1024));
+    let mut clients = server.crowd(&["guest-alice", "guest-bob"]);
+
+    let line = "x".repeat(300);
+    for _ in 0..5 {
+        clients[0].chat(DEFAULT_ROOM, &line).unwrap();
+        expect(&mut clients[1], "chat", is_chat(&line));
+    }
+
+    clients[1]
+        .send(&Message::Command {
+            name: "history".into(),
+            args: vec!["10".into()],
+            room: Some(DEFAULT_ROOM.into()),
+        })
+        .unwrap();
+    let msg = expect(&mut clients[1], "history", |m| {
+        matches!(m, Message::History { .. })
+    });
+    match msg {
+        Message::History { records, .. } => assert!(!records.is_empty() && records.len() < 5),
+        _ => unreachable!(),
+    }
+    expect(
+        &mut clients[1],
+        "notice",
+        |m| matches!(m, Message::System { text } if text.contains("too much to send")),
+    );
+
+    server.stop("Done");
+}
+
+#[test]
+fn a_flooding_client_doesnt_hold_up_everyone_else() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-alice", "guest-bob"]);
+
+    let mut flood = server.connect_raw();
+    let hello = Message::Hello {
+        version: PROTOCOL_VERSION,
+        features: vec![],
+    };
+    let join = Message::Join {
+        username: "guest-flood".into(),
+        password: None,
+    };
+    send_message(&mut flood, &hello).unwrap();
+    send_message(&mut flood, &join).unwrap();
+    expect(
+        &mut clients[0],
+        "join",
+        |m| matches!(m, Message::Join { username, .. } if username == "guest-flood"),
+    );
+
+    let pong = Message::Pong.encode().unwrap();
+    let pongs = encode_frame(pong.as_bytes(), 1024)
+        .unwrap()
+        .repeat(64 * 1024);
+    let stop = Arc::new(AtomicBool::new(false));
+    let flooding = {
+        let stop = stop.clone();
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                if flood.stream_mut().write_all(&pongs).is_err() {
+                    return;
+                }
+            }
+        })
+    };
+
+    // Give it time to get going
+    thread::sleep(Duration::from_millis(200));
+    clients[0].chat(DEFAULT_ROOM, "still there?").unwrap();
+    expect(&mut clients[1], "chat", is_chat("still there?"));
+
+    server.stop("Done");
+    stop.store(true, Ordering::SeqCst);
+    flooding.join().unwrap();
+}
+
+#[test]
+fn slow_readers_miss_the_oldest_messages() {
+    let builder = ServerBuilder::new().max_queue(8, OverflowPolicy::DropOldest);
+    let server = TestServer::with(builder);
+    let mut clients = server.crowd(&["guest-alice", "guest-slow"]);
+
+    for i in 0..FLOOD {
+        clients[0].chat(DEFAULT_ROOM, &flood_line(i)).unwrap();
+    }
+    clients[0].chat(DEFAULT_ROOM, "last").unwrap();
+
+    let mut seen = 0;
+    loop {
+        match clients[1].recv(Some(TIMEOUT)) {
+            Ok(Some(Message::Chat { text, .. })) if text == "last" => break,
+            Ok(Some(Message::Chat { .. })) => seen += 1,
+            Ok(Some(_)) => continue,
+            result => panic!("Slow reader got {:?} after {} chats", result, seen),
+        }
+    }
+    assert!(seen < FLOOD, "Nothing was dropped");
+
+    server.stop("Done");
+}
+
+#[test]
+fn slow_readers_are_disconnected() {
+    let builder = ServerBuilder::new().max_queue(8, OverflowPolicy::Disconnect);
+    let server = TestServer::with(builder);
+    let mut clients = server.crowd(&["guest-alice", "guest-bob", "guest-slow"]);
+
+    let is_dropped =
+        |m: &Message| matches!(m, Message::Goodbye { username, .. } if username == "guest-slow");
+    let mut dropped = false;
+    for i in 0..FLOOD {
+        let line = flood_line(i);
+        clients[0].chat(DEFAULT_ROOM, &line).unwrap();
+
+        // Bob keeps up, so he's still around to hear about it
+        let msg = expect(&mut clients[1], "chat", |m| {
+            is_chat(&line)(m) || is_dropped(m)
+        });
+        if is_dropped(&msg) {
+            dropped = true;
+            break;
+        }
+    }
+    assert!(dropped, "Slow reader wasn't dropped");
+
+    server.stop("Done");
+}
+
+#[test]
+fn users_that_stop_answering_are_dropped() {
+    let builder =
+        ServerBuilder::new().heartbeat(Duration::from_millis(100), Duration::from_millis(500));
+    let server = TestServer::with(builder);
+    let mut clients = server.crowd(&["guest-alice"]);
+
+    // A raw connection never answers the server's pings
+    let mut idle = server.connect_raw();
+    let hello = Message::Hello {
+        version: PROTOCOL_VERSION,
+        features: vec![],
+    };
+    let join = Message::Join {
+        username: "guest-idle".into(),
+        password: None,
+    };
+    send_message(&mut idle, &hello).unwrap();
+    send_message(&mut idle, &join).unwrap();
+    expect(
+        &mut clients[0],
+        "join",
+        |m| matches!(m, Message::Join { username, .. } if username == "guest-idle"),
+    );
+
+    // Alice answers hers while waiting, so only the idle user goes
+    expect(
+        &mut clients[0],
+        "goodbye",
+        |m| matches!(m, Message::Goodbye { username, .. } if username == "guest-idle"),
+    );
+
+    server.stop("Done");
+}
+
+fn join_room(room: &str) -> Message {
+    Message::JoinRoom {
+        username: String::new(),
+        room: room.into(),
+    }
+}
+
+fn part_room(room: &str) -> Message {
+    Message::PartRoom {
+        username: String::new(),
+        room: room.into(),
+    }
+}
+
+fn is_join_room<'a>(username: &'a str, room: &'a str) -> impl Fn(&Message) -> bool + 'a {
+    move |m| matches!(m, Message::JoinRoom { username: u, room: r } if u == username && r == room)
+}
+
+fn is_part_room<'a>(username: &'a str, room: &'a str) -> impl Fn(&Message) -> bool + 'a {
+    move |m| matches!(m, Message::PartRoom { username: u, room: r } if u == username && r == room)
+}
+
+fn list_rooms(client: &mut Client) -> Vec<(String, usize)> {
+    client.send(&Message::ListRooms).unwrap();
+    match expect(client, "rooms", |m| matches!(m, Message::Rooms { .. })) {
+        Message::Rooms { rooms } => rooms.into_iter().map(|r| (r.name, r.members)).collect(),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn rooms_can_be_joined_and_left() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-alice", "guest-bob"]);
+
+    clients[0].send(&join_room("#rust")).unwrap();
+    expect(
+        &mut clients[0],
+        "join",
+        is_join_room("guest-alice", "#rust"),
+    );
+    clients[1].send(&join_room("#rust")).unwrap();
+    expect(&mut clients[0], "join", is_join_room("guest-bob", "#rust"));
+    expect(&mut clients[1], "join", is_join_room("guest-bob", "#rust"));
+
+    assert_eq!(
+        list_rooms(&mut clients[0]),
+        vec![(DEFAULT_ROOM.to_owned(), 2), ("#rust".to_owned(), 2)]
+    );
+
+    // Whoever leaves hears about it too
+    clients[0].send(&part_room("#rust")).unwrap();
+    expect(
+        &mut clients[0],
+        "part",
+        is_part_room("guest-alice", "#rust"),
+    );
+    expect(
+        &mut clients[1],
+        "part",
+        is_part_room("guest-alice", "#rust"),
+    );
+
+    assert_eq!(
+        list_rooms(&mut clients[1]),
+        vec![(DEFAULT_ROOM.to_owned(), 2), ("#rust".to_owned(), 1)]
+    );
+
+    // Rooms go away with their last member
+    clients[1].send(&part_room("#rust")).unwrap();
+    expect(&mut clients[1], "part", is_part_room("guest-bob", "#rust"));
+    assert_eq!(
+        list_rooms(&mut clients[1]),
+        vec![(DEFAULT_ROOM.to_owned(), 2)]
+    );
+
+    server.stop("Done");
+}
+
+#[test]
+fn chat_stays_in_its_room() {
+    let server = TestServer::start();
+    let mut clients = server.crowd(&["guest-alice", "guest-bob", "guest-carol"]);
+
+    clients[0].send(&join_room("#rust")).unwrap();
+    expect(
+        &mut clients[0],
+        "join",
+        is_join_room("guest-alice", "#rust"),
+    );
+    clients[1].send(&join_room("#rust")).unwrap();
+    expect(&mut clients[1], "join", is_join_room("guest-bob", "#rust"));
+
+    clients[0].chat("#rust", "only for rust").unwrap();
+    expect(&mut clients[1], "chat", is_chat("only for rust"));
+    expect_none(&mut clients[2], "chat", is_chat("only for rust"));
+
+    // Once out of the room, its chat doesn't follow
+    clients[0].send(&part_room("#rust")).unwrap();
+    expect(
+        &mut clients[1],
+        "part",
+        is_part_room("guest-alice", "#rust"),
+    );
+    clients[1].chat("#rust", "alice is gone").unwrap();
+    expect_none(&mut clients[0], "chat", is_chat("alice is gone"));
+
+    server.stop("Done");
+}
@@ -0,0 +1,177 @@
+mod harness;
+
+use std::io::Write;
+use std::process::{Child, Command, Output, Stdio};
+
+use chat_rs::common::{Message, DEFAULT_ROOM};
+use chat_rs::Endpoint;
+use serde_json::Value;
+
+use harness::{expect, TestServer};
+
+/// Starts `join --pipe` against `server` with `input` on stdin.
+fn spawn_pipe(server: &TestServer, args: &[&str], input: &str) -> Child {
+    let port = match server.endpoint() {
+        Endpoint::Tcp(addr) => addr.port().to_string(),
+        #[cfg(unix)]
+        Endpoint::Unix(_) => unreachable!("Test servers listen on TCP"),
+    };
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_chat-rs"))
+        .args(["join", "--pipe", "-p", &port])
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("Client to start");
+
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+
+    child
+}
+
+/// Runs `join --pipe` against `server` with `input` on stdin, until it quits.
+fn pipe(server: &TestServer, args: &[&str], input: &str) -> Output {
+    spawn_pipe(server, args, input).wait_with_output().unwrap()
+}
+
+#[test]
+fn lines_are_sent_and_replies_printed() {
+    let server = TestServer::start();
+    let mut alice = server.join("guest-alice");
+
+    let output = pipe(
+        &server,
+        &["-u", "guest-ci", "--wait", "1"],
+        "build passed\n//etc/passwd is fine\n/who\n",
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout, "system\t2 online: guest-alice, guest-ci\n");
+
+    for text in ["build passed", "/etc/passwd is fine"] {
+        let msg = expect(&mut alice, "chat", |m| matches!(m, Message::Chat { .. }));
+        assert!(
+            matches!(msg, Message::Chat { from, text: t, .. } if from == "guest-ci" && t == text)
+        );
+    }
+    expect(
+        &mut alice,
+        "goodbye",
+        |m| matches!(m, Message::Goodbye { username, .. } if username == "guest-ci"),
+    );
+
+    server.stop("Done");
+}
+
+#[test]
+fn json_output_has_an_event_per_line() {
+    let server = TestServer::start();
+    let mut alice = server.join("guest-alice");
+
+    let child = spawn_pipe(
+        &server,
+        &["-u", "guest-ci", "--output", "json", "--wait", "2"],
+        "/msg guest-alice ping?\n",
+    );
+
+    expect(&mut alice, "direct", |m| {
+        matches!(m, Message::Direct { .. })
+    });
+    alice.chat(DEFAULT_ROOM, "pong").unwrap();
+    alice
+        .send(&Message::Direct {
+            from: String::new(),
+            to: "guest-ci".into(),
+            text: "bye".into(),
+        })
+        .unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{:?}", output);
+
+    let events: Vec<Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).expect("A JSON object per line"))
+        .collect();
+    assert_eq!(events.len(), 2, "{:?}", events);
+
+    assert_eq!(events[0]["type"], "chat");
+    assert_eq!(events[0]["sender"], "guest-alice");
+    assert_eq!(events[0]["room"], DEFAULT_ROOM);
+    assert_eq!(events[0]["text"], "pong");
+    assert!(events[0]["timestamp"].is_string());
+
+    assert_eq!(events[1]["type"], "direct");
+    assert_eq!(events[1]["sender"], "guest-alice");
+    assert_eq!(events[1]["to"], "guest-ci");
+    assert_eq!(events[1]["room"], Value::Null);
+    assert_eq!(events[1]["text"], "bye");
+
+    server.stop("Done");
+}
+
+#[test]
+fn too_few_replies_is_an_error() {
+    let server = TestServer::start();
+
+    let output = pipe(
+        &server,
+        &["-u", "guest-ci", "--wait", "1", "--timeout", "1"],
+        "anyone?\n",
+    );
+    assert_eq!(output.status.code(), Some(4));
+    assert!(output.stdout.is_empty());
+
+    server.stop("Done");
+}
+
+#[test]
+fn lines_follow_the_room_the_server_put_us_in() {
+    let server = TestServer::start();
+    let mut alice = server.join("guest-alice");
+    for room in ["#ci", "#deploy"] {
+        alice
+            .send(&Message::JoinRoom {
+                username: String::new(),
+                room: room.into(),
+            })
+            .unwrap();
+        expect(
+            &mut alice,
+            "join",
+            |m| matches!(m, Message::JoinRoom { room: r, .. } if r == room),
+        );
+    }
+
+    // The second join is refused, so lines keep going to #deploy
+    let output = pipe(
+        &server,
+        &["-u", "guest-ci", "--room", "#ci"],
+        "build passed\n/join #deploy\nshipped\n/join deploy\nrolled back\n",
+    );
+    assert!(output.status.success(), "{:?}", output);
+
+    for (room, text) in [
+        ("#ci", "build passed"),
+        ("#deploy", "shipped"),
+        ("#deploy", "rolled back"),
+    ] {
+        let msg = expect(&mut alice, "chat", |m| matches!(m, Message::Chat { .. }));
+        assert!(
+            matches!(&msg, Message::Chat { room: Some(r), text: t, .. } if r == room && t == text),
+            "{:?}",
+            msg
+        );
+    }
+
+    server.stop("Done");
+}
@@ -0,0 +1,165 @@
+use std::convert::TryFrom;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rcgen::{BasicConstraints, CertificateParams, IsCa, Issuer, KeyPair};
+use rustls::pki_types::{CertificateDer, ServerName};
+use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+use serde_json::{json, Value};
+
+/// A server running out of the built binary, killed once dropped.
+struct Server {
+    child: Child,
+    port: u16,
+    dir: PathBuf,
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// A CA that signs a certificate for 127.0.0.1.
+struct Certs {
+    ca: CertificateDer<'static>,
+    cert_pem: String,
+    key_pem: String,
+}
+
+fn make_certs() -> Certs {
+    let ca_key = KeyPair::generate().unwrap();
+    let mut ca_params = CertificateParams::new(vec![]).unwrap();
+    ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    let ca = ca_params.self_signed(&ca_key).unwrap();
+
+    let key = KeyPair::generate().unwrap();
+    let issuer = Issuer::new(ca_params, ca_key);
+    let cert = CertificateParams::new(vec!["127.0.0.1".into()])
+        .unwrap()
+        .signed_by(&key, &issuer)
+        .unwrap();
+
+    Certs {
+        ca: ca.der().clone(),
+        cert_pem: cert.pem(),
+        key_pem: key.serialize_pem(),
+    }
+}
+
+fn start_server(name: &str, certs: &Certs) -> Server {
+    let dir = env::temp_dir().join(format!("chat-rs-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("cert.pem"), &certs.cert_pem).unwrap();
+    fs::write(dir.join("key.pem"), &certs.key_pem).unwrap();
+
+    let port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let child = Command::new(env!("CARGO_BIN_EXE_chat-rs"))
+        .arg("server")
+        .args(["-p", &port.to_string()])
+        .arg("--cert")
+        .arg(dir.join("cert.pem"))
+        .arg("--key")
+        .arg(dir.join("key.pem"))
+        .arg("--history-file")
+        .arg(dir.join("history.jsonl"))
+        .arg("--accounts-file")
+        .arg(dir.join("accounts.jsonl"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    Server { child, port, dir }
+}
+
+fn connect(
+    server: &Server,
+    ca: &CertificateDer<'static>,
+) -> std::io::Result<StreamOwned<ClientConnection, TcpStream>> {
+    let mut roots = RootCertStore::empty();
+    roots.add(ca.clone()).unwrap();
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let socket = loop {
+        match TcpStream::connect(("127.0.0.1", server.port)) {
+            Ok(socket) => break socket,
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(50)),
+            Err(e) => panic!("Server never came up: {}", e),
+        }
+    };
+    socket
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let name = ServerName::try_from("127.0.0.1").unwrap();
+    let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+    let mut socket = socket;
+    while conn.is_handshaking() {
+        conn.complete_io(&mut socket)?;
+    }
+
+    Ok(StreamOwned::new(conn, socket))
+}
+
+fn send(stream: &mut impl Write, msg: Value) {
+    let payload = msg.to_string();
+    stream
+        .write_all(&(payload.len() as u32).to_be_bytes())
+        .unwrap();
+    stream.write_all(payload.as_bytes()).unwrap();
+    stream.flush().unwrap();
+}
+
+fn recv(stream: &mut impl Read) -> Value {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header).unwrap();
+    let mut payload = vec![0u8; u32::from_be_bytes(header) as usize];
+    stream.read_exact(&mut payload).unwrap();
+
+    serde_json::from_slice(&payload).unwrap()
+}
+
+#[test]
+fn handshake_over_tls() {
+    let certs = make_certs();
+    let server = start_server("tls", &certs);
+    let mut stream = connect(&server, &certs.ca).expect("TLS handshake");
+
+    send(&mut stream, json!({"type": "hello", "version": 1}));
+    assert_eq!(recv(&mut stream)["type"], "welcome");
+
+    send(
+        &mut stream,
+        json!({"type": "join", "username": "guest-tls"}),
+    );
+    let joined = recv(&mut stream);
+    assert_eq!(joined["type"], "join");
+    assert_eq!(joined["username"], "guest-tls");
+}
+
+#[test]
+fn unknown_ca_is_refused() {
+    let certs = make_certs();
+    let server = start_server("tls-unknown-ca", &certs);
+    let other = make_certs();
+
+    assert!(connect(&server, &other.ca).is_err());
+}
@@ -0,0 +1,58 @@
+#![cfg(unix)]
+
+use std::env;
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::process;
+
+use chat_rs::transport::Listener;
+use chat_rs::Endpoint;
+
+/// A path of its own for each test, gone once it's done.
+struct Scratch(PathBuf);
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        Scratch(env::temp_dir().join(format!("chat-rs-{}-{}", process::id(), name)))
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+#[test]
+fn files_that_arent_sockets_are_left_alone() {
+    let path = Scratch::new("notes.txt");
+    fs::write(&path.0, "keep me").unwrap();
+
+    let result = Listener::bind(&Endpoint::Unix(path.0.clone()));
+
+    assert_eq!(
+        result.err().map(|e| e.kind()),
+        Some(ErrorKind::AlreadyExists)
+    );
+    assert_eq!(fs::read_to_string(&path.0).unwrap(), "keep me");
+}
+
+#[test]
+fn sockets_left_behind_are_replaced() {
+    let path = Scratch::new("stale.sock");
+    drop(UnixListener::bind(&path.0).unwrap());
+
+    Listener::bind(&Endpoint::Unix(path.0.clone())).expect("Stale socket to be replaced");
+}
+
+#[test]
+fn sockets_in_use_are_kept() {
+    let path = Scratch::new("busy.sock");
+    let _busy = UnixListener::bind(&path.0).unwrap();
+
+    let result = Listener::bind(&Endpoint::Unix(path.0.clone()));
+
+    assert_eq!(result.err().map(|e| e.kind()), Some(ErrorKind::AddrInUse));
+}